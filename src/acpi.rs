@@ -0,0 +1,185 @@
+use crate::memory;
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+// Real mode segment of the EBDA, whose first KiB may hold the RSDP
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+// Otherwise it's in the BIOS area, on a 16 byte boundary
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+// Every system description table starts with a header of this size, its length at offset 4
+const SDT_HEADER_SIZE: u64 = 36;
+
+const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+// Interrupt source override flags
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+const ISA_IRQ_COUNT: usize = 16;
+
+// ACPI tables don't align their fields, so everything is read unaligned
+fn read<T: Copy>(addr: u64) -> T {
+    let offset = memory::physical_memory_offset().expect("physical memory isn't mapped");
+    unsafe { ptr::read_unaligned((offset + addr).as_ptr()) }
+}
+
+fn checksum_is_valid(addr: u64, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i))) == 0
+}
+
+fn table_len(addr: u64) -> u64 {
+    u64::from(read::<u32>(addr + 4))
+}
+
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(read::<u16>(EBDA_SEGMENT_POINTER)) << 4;
+    let ebda_range = if ebda == 0 { 0..0 } else { ebda..ebda + 1024 };
+
+    ebda_range
+        .step_by(16)
+        .chain((BIOS_AREA_START..BIOS_AREA_END).step_by(16))
+        .find(|&addr| read::<[u8; 8]>(addr) == RSDP_SIGNATURE && checksum_is_valid(addr, 20))
+}
+
+/// Returns the physical address of the first valid ACPI table with the given signature
+///
+/// Needs the bootloader's mapping of physical memory.
+pub fn find_table(signature: [u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    // ACPI 2.0 added the XSDT, with 64-bit table pointers
+    let (root, entry_size) = if read::<u8>(rsdp + 15) >= 2 {
+        (read::<u64>(rsdp + 24), 8)
+    } else {
+        (u64::from(read::<u32>(rsdp + 16)), 4)
+    };
+    if !checksum_is_valid(root, table_len(root)) {
+        return None;
+    }
+
+    (root + SDT_HEADER_SIZE..root + table_len(root))
+        .step_by(entry_size)
+        .map(|entry| {
+            if entry_size == 8 {
+                read::<u64>(entry)
+            } else {
+                u64::from(read::<u32>(entry))
+            }
+        })
+        .find(|&table| {
+            read::<[u8; 4]>(table) == signature && checksum_is_valid(table, table_len(table))
+        })
+        .map(PhysAddr::new)
+}
+
+/// An I/O APIC, as listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Where an ISA IRQ line is wired to on the I/O APICs, and how it signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrq {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The interrupt controllers described by the MADT
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub io_apics: Vec<IoApic>,
+    /// Indexed by ISA IRQ, with the interrupt source overrides applied
+    pub isa_irqs: [IsaIrq; ISA_IRQ_COUNT],
+}
+
+impl Madt {
+    /// Finds and parses the MADT, returning None if there's no valid one
+    pub fn parse() -> Option<Self> {
+        let table = find_table(MADT_SIGNATURE)?.as_u64();
+        let end = table + table_len(table);
+
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(u64::from(read::<u32>(table + SDT_HEADER_SIZE))),
+            io_apics: Vec::new(),
+            // Without an override, ISA IRQs are identity mapped, edge triggered and active high
+            isa_irqs: [IsaIrq {
+                gsi: 0,
+                active_low: false,
+                level_triggered: false,
+            }; ISA_IRQ_COUNT],
+        };
+        for (irq, isa_irq) in madt.isa_irqs.iter_mut().enumerate() {
+            isa_irq.gsi = irq as u32;
+        }
+
+        // Entries follow the header, the local APIC address and the flags
+        let mut entry = table + SDT_HEADER_SIZE + 8;
+        while entry + 2 <= end {
+            let len = u64::from(read::<u8>(entry + 1));
+            if len < 2 || entry + len > end {
+                return None;
+            }
+
+            match read::<u8>(entry) {
+                MADT_IO_APIC => madt.io_apics.push(IoApic {
+                    id: read(entry + 2),
+                    address: PhysAddr::new(u64::from(read::<u32>(entry + 4))),
+                    gsi_base: read(entry + 8),
+                }),
+                MADT_INTERRUPT_SOURCE_OVERRIDE => {
+                    let bus = read::<u8>(entry + 2);
+                    let source = read::<u8>(entry + 3) as usize;
+                    let flags = read::<u16>(entry + 8);
+                    if bus == 0 && source < ISA_IRQ_COUNT {
+                        // "Conforms to the bus" means ISA's edge triggered and active high
+                        madt.isa_irqs[source] = IsaIrq {
+                            gsi: read(entry + 4),
+                            active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+                            level_triggered: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+                        };
+                    }
+                }
+                MADT_LOCAL_APIC_OVERRIDE => {
+                    madt.local_apic = PhysAddr::new(read(entry + 4));
+                }
+                _ => {}
+            }
+            entry += len;
+        }
+
+        Some(madt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    test!(parse_qemu_madt {
+        let madt = Madt::parse().unwrap();
+        assert_eq!(madt.local_apic, PhysAddr::new(0xfee0_0000));
+        assert_eq!(madt.io_apics.len(), 1);
+        assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xfec0_0000));
+        assert_eq!(madt.io_apics[0].gsi_base, 0);
+
+        // QEMU moves the PIT to GSI 2, and makes its PCI interrupts level triggered
+        assert_eq!(madt.isa_irqs[0].gsi, 2);
+        assert!(!madt.isa_irqs[0].level_triggered);
+        assert_eq!(madt.isa_irqs[1].gsi, 1);
+        assert!(madt.isa_irqs[9].level_triggered);
+    });
+}
@@ -1,10 +1,18 @@
+pub mod buddy;
 pub mod bump;
+pub mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
 
+use crate::memory::{
+    self,
+    vma::{RegionKind, ADDRESS_SPACE},
+    MapError,
+};
+use core::fmt;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
@@ -23,42 +31,210 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
+}
+
+impl<A: HeapStats> Locked<A> {
+    pub fn stats(&self) -> AllocStats {
+        self.lock().stats()
+    }
+}
+
+/// Usage numbers reported by every allocator backend
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+    pub bytes_used: usize,
+    pub bytes_free: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub high_water_mark: usize,
+    /// Free list length of each `fixed_size_block::BLOCK_SIZES` class, if the backend has them
+    pub free_blocks: Option<[usize; fixed_size_block::BLOCK_SIZES.len()]>,
+}
+
+impl AllocStats {
+    const fn new() -> Self {
+        Self {
+            bytes_used: 0,
+            bytes_free: 0,
+            allocations: 0,
+            frees: 0,
+            high_water_mark: 0,
+            free_blocks: None,
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.bytes_used += size;
+        self.bytes_free = self.bytes_free.saturating_sub(size);
+        self.allocations += 1;
+        self.high_water_mark = self.high_water_mark.max(self.bytes_used);
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        // A bad or double free must not panic in here, where the panic handler can't get the stats
+        self.bytes_used = self.bytes_used.saturating_sub(size);
+        self.bytes_free += size;
+        self.frees += 1;
+    }
+}
+
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} B used, {} B free, {} B peak, {} allocs, {} frees",
+            self.bytes_used, self.bytes_free, self.high_water_mark, self.allocations, self.frees
+        )?;
+        if let Some(free_blocks) = self.free_blocks {
+            write!(f, "\nfree blocks:")?;
+            for (size, count) in fixed_size_block::BLOCK_SIZES.iter().zip(free_blocks.iter()) {
+                write!(f, " {}B:{}", size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait HeapStats {
+    fn stats(&self) -> AllocStats;
 }
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+#[cfg(not(feature = "alloc-bump"))]
 pub const HEAP_SIZE: usize = 100 * 1024;
+// The bump allocator can't grow, and only reuses memory once every allocation is freed, which
+// never happens while the kernel holds long-lived boxes. Give it room for the whole test suite.
+#[cfg(feature = "alloc-bump")]
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+// The heap maps more pages on demand until it reaches this size
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+#[cfg(not(any(
+    feature = "alloc-fixed-block",
+    feature = "alloc-linked-list",
+    feature = "alloc-bump",
+    feature = "alloc-buddy"
+)))]
+compile_error!("one of the alloc-* features must be enabled to pick a global allocator");
 
+#[cfg(feature = "alloc-bump")]
+type Backend = bump::BumpAllocator;
+#[cfg(all(feature = "alloc-linked-list", not(feature = "alloc-bump")))]
+type Backend = linked_list::LinkedListAllocator;
+#[cfg(all(
+    feature = "alloc-buddy",
+    not(any(feature = "alloc-bump", feature = "alloc-linked-list"))
+))]
+type Backend = buddy::BuddyAllocator;
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-buddy"
+)))]
+type Backend = fixed_size_block::FixedSizeBlockAllocator;
+
+#[cfg(not(feature = "alloc-debug"))]
 #[global_allocator]
-static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
-    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
+static ALLOCATOR: Locked<Backend> = Locked::new(Backend::new());
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+#[cfg(feature = "alloc-debug")]
+#[global_allocator]
+static ALLOCATOR: debug::DebugAllocator<Locked<Backend>> =
+    debug::DebugAllocator::new(Locked::new(Backend::new()));
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
-        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-    }
+#[cfg(not(feature = "alloc-debug"))]
+fn backend() -> &'static Locked<Backend> {
+    &ALLOCATOR
+}
+
+#[cfg(feature = "alloc-debug")]
+fn backend() -> &'static Locked<Backend> {
+    ALLOCATOR.inner()
+}
+
+pub fn init_heap<A>(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MapError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    // Reserve room for the heap to grow into
+    ADDRESS_SPACE
+        .lock()
+        .reserve_at(
+            VirtAddr::new(HEAP_START as u64),
+            HEAP_MAX_SIZE as u64,
+            RegionKind::Heap,
+            PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+        )
+        .expect("heap region is already in use");
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        let mut allocator = backend().lock();
+        allocator.init(HEAP_START, HEAP_SIZE);
+        // Only the fixed size block allocator knows how to grow
+        #[cfg(not(any(
+            feature = "alloc-bump",
+            feature = "alloc-linked-list",
+            feature = "alloc-buddy"
+        )))]
+        allocator.set_max_size(HEAP_MAX_SIZE);
     }
 
     Ok(())
 }
 
+/// Returns the global allocator's stats, or `None` if it's locked (e.g. when panicking inside it)
+pub fn stats() -> Option<AllocStats> {
+    backend().try_lock().map(|allocator| allocator.stats())
+}
+
+fn map_heap_pages<A>(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MapError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size;
+        let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
+        let heap_end_page = Page::<Size4KiB>::containing_address(heap_end - 1u64);
+        Page::range(heap_start_page, heap_end_page + 1)
+    };
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
+    memory::map_range(page_range, flags, mapper, frame_allocator)
+}
+
+/// Maps `size` more bytes of heap memory starting at `heap_end` using the kernel's mapper and
+/// frame allocator
+///
+/// Called with the allocator locked, so it fails instead of spinning if the memory subsystem is
+/// not initialized yet or is currently locked by someone else.
+fn map_heap_extension(heap_end: usize, size: usize) -> Result<(), ()> {
+    let mut mapper = memory::MAPPER.try_lock().ok_or(())?;
+    let mut frame_allocator = memory::FRAME_ALLOCATOR.try_lock().ok_or(())?;
+
+    match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => {
+            map_heap_pages(heap_end, size, mapper, frame_allocator).map_err(|_| ())
+        }
+        _ => Err(()),
+    }
+}
+
 /// Aligns the address upwards to a specific alignment boundary
 ///
 /// `align` should be power of 2
@@ -68,7 +244,8 @@ fn align_up(addr: usize, align: usize) -> usize {
 
 #[cfg(test)]
 mod test {
-    use alloc::{boxed::Box, vec::Vec};
+    use super::HEAP_SIZE;
+    use alloc::{boxed::Box, vec, vec::Vec};
 
     test!(simple_alloc {
         let heap_val = Box::new(41);
@@ -99,4 +276,30 @@ mod test {
         }
         assert_eq!(*long_lived, 10);
     });
+
+    #[cfg(not(any(
+        feature = "alloc-bump",
+        feature = "alloc-linked-list",
+        feature = "alloc-buddy"
+    )))]
+    test!(grow_past_initial_size {
+        let vec = vec![7u8; 2 * HEAP_SIZE];
+        assert_eq!(vec.len(), 2 * HEAP_SIZE);
+        assert!(vec.iter().all(|&b| b == 7));
+    });
+
+    test!(stats_track_allocations {
+        let before = super::stats().unwrap();
+        let val = Box::new(5u64);
+        let during = super::stats().unwrap();
+        assert_eq!(*val, 5);
+        assert_eq!(during.allocations, before.allocations + 1);
+        assert!(during.bytes_used > before.bytes_used);
+        assert!(during.high_water_mark >= during.bytes_used);
+
+        drop(val);
+        let after = super::stats().unwrap();
+        assert_eq!(after.frees, before.frees + 1);
+        assert_eq!(after.bytes_used, before.bytes_used);
+    });
 }
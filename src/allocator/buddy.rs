@@ -0,0 +1,295 @@
+use super::{AllocStats, HeapStats, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+// Smallest block handed out, must be a power of 2 that fits a ListNode
+const MIN_BLOCK_SIZE: usize = 16;
+// Blocks range from MIN_BLOCK_SIZE to MIN_BLOCK_SIZE << (ORDERS - 1)
+const ORDERS: usize = 32;
+
+/// Splits memory into power-of-2 blocks and merges freed blocks with their buddies
+///
+/// Every block is naturally aligned to its own size, so the buddy of a block is found by flipping
+/// its size bit in the address.
+pub struct BuddyAllocator {
+    free_lists: [Option<&'static mut ListNode>; ORDERS],
+    stats: AllocStats,
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [None; ORDERS],
+            stats: AllocStats::new(),
+        }
+    }
+
+    /// Initializes allocator
+    ///
+    /// Unsafe since it can only be called once and given memory range must be unused
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let mut addr = super::align_up(heap_start, MIN_BLOCK_SIZE);
+        let end = heap_start + heap_size;
+
+        // Carve the heap into the largest naturally aligned blocks that fit
+        while end.saturating_sub(addr) >= MIN_BLOCK_SIZE {
+            let mut order = 0;
+            while order + 1 < ORDERS {
+                let size = block_size(order + 1);
+                if addr % size != 0 || end - addr < size {
+                    break;
+                }
+                order += 1;
+            }
+
+            self.push(order, addr);
+            addr += block_size(order);
+        }
+
+        self.stats = AllocStats::new();
+        self.stats.bytes_free = addr - super::align_up(heap_start, MIN_BLOCK_SIZE);
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let node = ListNode {
+            next: self.free_lists[order].take(),
+        };
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        self.free_lists[order] = Some(&mut *node_ptr);
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        self.free_lists[order].take().map(|node| {
+            self.free_lists[order] = node.next.take();
+            node as *mut ListNode as usize
+        })
+    }
+
+    /// Removes the block at the given address from a free list, returning whether it was there
+    fn remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut current = &mut self.free_lists[order];
+        while current
+            .as_ref()
+            .map_or(false, |node| &**node as *const ListNode as usize != addr)
+        {
+            current = &mut current.as_mut().unwrap().next;
+        }
+
+        match current.take() {
+            Some(node) => {
+                *current = node.next.take();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
+        // Find the smallest free block that's big enough
+        let found = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(found).unwrap();
+
+        // Split it down, freeing the upper halves
+        for o in (order..found).rev() {
+            unsafe { self.push(o, addr + block_size(o)) };
+        }
+
+        Some(addr)
+    }
+
+    unsafe fn free_block(&mut self, mut order: usize, mut addr: usize) {
+        while order + 1 < ORDERS {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(order, buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(order, addr);
+    }
+}
+
+fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+/// Returns order of the smallest block that can hold the given allocation
+fn block_order(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK_SIZE)
+        .checked_next_power_of_two()?;
+    let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+
+    if order < ORDERS {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+impl HeapStats for BuddyAllocator {
+    fn stats(&self) -> AllocStats {
+        self.stats
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let order = match block_order(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+
+        match allocator.alloc_block(order) {
+            Some(addr) => {
+                allocator.stats.record_alloc(block_size(order));
+                addr as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        let order = block_order(&layout).expect("Layout was never allocated");
+
+        allocator.stats.record_dealloc(block_size(order));
+        allocator.free_block(order, ptr as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spin::Once;
+
+    const HEAP_SIZE: usize = 64 * 1024;
+
+    // Aligned to its size, so `init` makes the whole heap a single block
+    #[repr(C, align(65536))]
+    struct Heap([u8; HEAP_SIZE]);
+
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+    static ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+    static INIT: Once<()> = Once::new();
+
+    // Tests share one heap, so each one must free everything it allocates
+    fn allocator() -> &'static Locked<BuddyAllocator> {
+        INIT.call_once(|| unsafe {
+            ALLOCATOR
+                .lock()
+                .init(&mut HEAP as *mut Heap as usize, HEAP_SIZE)
+        });
+        &ALLOCATOR
+    }
+
+    test!(simple_alloc {
+        let allocator = allocator();
+        let layout = Layout::new::<u64>();
+        unsafe {
+            let ptr = allocator.alloc(layout) as *mut u64;
+            assert!(!ptr.is_null());
+            ptr.write(41);
+            assert_eq!(ptr.read(), 41);
+            allocator.dealloc(ptr as *mut u8, layout);
+        }
+    });
+
+    test!(aligned_alloc {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(100, 1024).unwrap();
+        unsafe {
+            let small = allocator.alloc(Layout::new::<u8>());
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 1024, 0);
+            allocator.dealloc(ptr, layout);
+            allocator.dealloc(small, Layout::new::<u8>());
+        }
+    });
+
+    test!(many_allocs {
+        let allocator = allocator();
+        let layout = Layout::new::<[u64; 4]>();
+        let mut ptrs = [ptr::null_mut(); 256];
+        unsafe {
+            for (i, ptr) in ptrs.iter_mut().enumerate() {
+                *ptr = allocator.alloc(layout);
+                assert!(!ptr.is_null());
+                (*ptr as *mut usize).write(i);
+            }
+            for (i, &ptr) in ptrs.iter().enumerate() {
+                assert_eq!((ptr as *mut usize).read(), i);
+                allocator.dealloc(ptr, layout);
+            }
+        }
+    });
+
+    test!(merges_buddies {
+        let allocator = allocator();
+        let small = Layout::from_size_align(48, 8).unwrap();
+        let mut ptrs = [ptr::null_mut(); 64];
+        unsafe {
+            for ptr in ptrs.iter_mut() {
+                *ptr = allocator.alloc(small);
+                assert!(!ptr.is_null());
+            }
+            for &ptr in ptrs.iter().step_by(2).chain(ptrs.iter().skip(1).step_by(2)) {
+                allocator.dealloc(ptr, small);
+            }
+
+            // Everything merged back, so the whole heap is one block again
+            let full = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+            let ptr = allocator.alloc(full);
+            assert_eq!(ptr as usize, &mut HEAP as *mut Heap as usize);
+            allocator.dealloc(ptr, full);
+        }
+    });
+
+    test!(too_big {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(2 * HEAP_SIZE, 8).unwrap();
+        assert!(unsafe { allocator.alloc(layout) }.is_null());
+    });
+
+    // The same kind of cases as `allocator::test`, against the global allocator
+    #[cfg(all(
+        feature = "alloc-buddy",
+        not(any(feature = "alloc-bump", feature = "alloc-linked-list"))
+    ))]
+    mod global {
+        use crate::allocator;
+        use alloc::{boxed::Box, vec, vec::Vec};
+
+        #[repr(C, align(4096))]
+        struct Page([u8; 4096]);
+
+        test!(aligned_box {
+            let page = Box::new(Page([3; 4096]));
+            assert_eq!(&*page as *const Page as usize % 4096, 0);
+            assert!(page.0.iter().all(|&b| b == 3));
+        });
+
+        test!(freed_blocks_merge {
+            let before = allocator::stats().unwrap();
+            let boxes: Vec<Box<u64>> = (0..1000).map(Box::new).collect();
+            assert!(boxes.iter().enumerate().all(|(i, b)| **b == i as u64));
+            drop(boxes);
+            assert_eq!(allocator::stats().unwrap().bytes_used, before.bytes_used);
+
+            // Only fits if the small blocks merged back into big ones
+            let big = vec![1u8; 32 * 1024];
+            assert!(big.iter().all(|&b| b == 1));
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, AllocStats, HeapStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,6 +7,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    stats: AllocStats,
 }
 
 impl BumpAllocator {
@@ -16,6 +17,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            stats: AllocStats::new(),
         }
     }
 
@@ -27,6 +29,18 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
         self.allocations = 0;
+        self.stats = AllocStats::new();
+        self.stats.bytes_free = heap_size;
+    }
+}
+
+impl HeapStats for BumpAllocator {
+    fn stats(&self) -> AllocStats {
+        // Freed memory only becomes available again once every allocation is gone
+        AllocStats {
+            bytes_free: self.heap_end - self.next,
+            ..self.stats
+        }
     }
 }
 
@@ -45,13 +59,15 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = end;
             bump.allocations += 1;
+            bump.stats.record_alloc(layout.size());
             start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock();
 
+        bump.stats.record_dealloc(layout.size());
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
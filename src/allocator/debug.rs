@@ -0,0 +1,194 @@
+use super::align_up;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, slice};
+
+// Bytes of canaries placed on each side of every allocation
+const RED_ZONE_SIZE: usize = 16;
+const CANARY: u8 = 0xca;
+// Freed memory is filled with this, so stale reads show up as a recognizable pattern
+pub const POISON: u8 = 0x6b;
+
+const LIVE: u64 = 0xa110_ca7e_d11f_e000;
+const FREED: u64 = 0xf4ee_d0d0_dead_0000;
+
+// Sits right before the user data, after the front red zone. The backend may write its own free
+// list node at the start of a freed block, so the header is kept away from there to survive
+// until a double free is detected.
+#[repr(C)]
+struct Header {
+    state: u64,
+    size: usize,
+}
+
+/// Wraps another allocator, surrounding every allocation with canary red zones and poisoning freed
+/// memory
+///
+/// Corrupted canaries, double frees and mismatched layouts are caught on `dealloc` and cause a
+/// panic with the address and `Layout`. Double frees are only detected until the backend hands
+/// the same block out again.
+pub struct DebugAllocator<A> {
+    inner: A,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// Returns the offset of the user data from the start of the block, and the block's layout
+fn block_layout(layout: &Layout) -> Option<(usize, Layout)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let front = align_up(RED_ZONE_SIZE + mem::size_of::<Header>(), align);
+    let size = front
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    let block = Layout::from_size_align(size, align).ok()?;
+    Some((front, block))
+}
+
+unsafe fn header_ptr(ptr: *mut u8) -> *mut Header {
+    ptr.sub(mem::size_of::<Header>()) as *mut Header
+}
+
+unsafe fn red_zones(ptr: *mut u8, size: usize) -> (&'static mut [u8], &'static mut [u8]) {
+    let front = slice::from_raw_parts_mut(
+        ptr.sub(mem::size_of::<Header>() + RED_ZONE_SIZE),
+        RED_ZONE_SIZE,
+    );
+    let back = slice::from_raw_parts_mut(ptr.add(size), RED_ZONE_SIZE);
+    (front, back)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (front, block) = match block_layout(&layout) {
+            Some(block) => block,
+            None => return ptr::null_mut(),
+        };
+
+        let block_ptr = self.inner.alloc(block);
+        if block_ptr.is_null() {
+            return block_ptr;
+        }
+
+        let ptr = block_ptr.add(front);
+        header_ptr(ptr).write(Header {
+            state: LIVE,
+            size: layout.size(),
+        });
+        let (front_zone, back_zone) = red_zones(ptr, layout.size());
+        ptr::write_bytes(front_zone.as_mut_ptr(), CANARY, RED_ZONE_SIZE);
+        ptr::write_bytes(back_zone.as_mut_ptr(), CANARY, RED_ZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (front, block) =
+            block_layout(&layout).expect("DebugAllocator: layout could never be allocated");
+
+        let header = &mut *header_ptr(ptr);
+        match header.state {
+            LIVE => {}
+            FREED => panic!(
+                "Heap corruption: double free of {:p} with {:?}",
+                ptr, layout
+            ),
+            _ => panic!(
+                "Heap corruption: header overwritten or invalid pointer {:p} with {:?}",
+                ptr, layout
+            ),
+        }
+        if header.size != layout.size() {
+            panic!(
+                "Heap corruption: {:p} allocated with size {} but freed with {:?}",
+                ptr, header.size, layout
+            );
+        }
+
+        let (front_zone, back_zone) = red_zones(ptr, layout.size());
+        if front_zone.iter().any(|&b| b != CANARY) {
+            panic!(
+                "Heap corruption: underflow before {:p} with {:?}",
+                ptr, layout
+            );
+        }
+        if back_zone.iter().any(|&b| b != CANARY) {
+            panic!(
+                "Heap corruption: overflow after {:p} with {:?}",
+                ptr, layout
+            );
+        }
+
+        header.state = FREED;
+        ptr::write_bytes(ptr, POISON, layout.size());
+        self.inner.dealloc(ptr.sub(front), block);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::allocator::{linked_list::LinkedListAllocator, Locked};
+    use spin::Once;
+
+    const HEAP_SIZE: usize = 16 * 1024;
+
+    #[repr(C, align(4096))]
+    struct Heap([u8; HEAP_SIZE]);
+
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+    static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> =
+        DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+    static INIT: Once<()> = Once::new();
+
+    fn allocator() -> &'static DebugAllocator<Locked<LinkedListAllocator>> {
+        INIT.call_once(|| unsafe {
+            ALLOCATOR
+                .inner()
+                .lock()
+                .init(&mut HEAP as *mut Heap as usize, HEAP_SIZE)
+        });
+        &ALLOCATOR
+    }
+
+    test!(red_zones_surround_allocation {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            ptr::write_bytes(ptr, 0, layout.size());
+            let (front, back) = red_zones(ptr, layout.size());
+            assert!(front.iter().chain(back.iter()).all(|&b| b == CANARY));
+            allocator.dealloc(ptr, layout);
+        }
+    });
+
+    test!(respects_alignment {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(64, 256).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert_eq!(ptr as usize % 256, 0);
+            allocator.dealloc(ptr, layout);
+        }
+    });
+
+    test!(poisons_freed_memory {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            ptr::write_bytes(ptr, 0, layout.size());
+            allocator.dealloc(ptr, layout);
+            // The backend only reuses the start of the block, far from the user data
+            let data = slice::from_raw_parts(ptr, layout.size());
+            assert!(data.iter().all(|&b| b == POISON));
+        }
+    });
+}
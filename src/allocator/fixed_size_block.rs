@@ -1,4 +1,4 @@
-use super::Locked;
+use super::{align_up, AllocStats, HeapStats, Locked, PAGE_SIZE};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,11 +7,13 @@ struct ListNode {
 }
 
 // Must all be powers of 2
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    max_size: usize,
+    stats: AllocStats,
 }
 
 impl FixedSizeBlockAllocator {
@@ -19,19 +21,51 @@ impl FixedSizeBlockAllocator {
         Self {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            max_size: 0,
+            stats: AllocStats::new(),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        self.max_size = heap_size;
+        self.stats = AllocStats::new();
+        self.stats.bytes_free = heap_size;
+    }
+
+    /// Lets the heap grow up to `max_size` bytes by mapping more pages after its end
+    ///
+    /// The virtual memory after the current heap end must be unused up to that size.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        loop {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if self.grow(layout).is_err() {
+                return ptr::null_mut();
+            }
         }
     }
+
+    /// Extends the heap by enough pages to hold the given allocation
+    fn grow(&mut self, layout: Layout) -> Result<(), ()> {
+        let by = layout.size().checked_add(layout.align()).ok_or(())?;
+        let by = align_up(by, PAGE_SIZE);
+        // The max size may have been set below the current size
+        let room = self.max_size.checked_sub(self.fallback_allocator.size());
+        if room.map_or(true, |room| room < by) {
+            return Err(());
+        }
+
+        super::map_heap_extension(self.fallback_allocator.top(), by)?;
+        unsafe { self.fallback_allocator.extend(by) };
+        self.stats.bytes_free += by;
+        Ok(())
+    }
 }
 
 /// Returns index of smallest block size that can hold the given allocation
@@ -40,11 +74,43 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= size)
 }
 
+/// Returns how many bytes of the heap the given allocation takes up
+fn alloc_size(layout: &Layout) -> usize {
+    match list_index(layout) {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size(),
+    }
+}
+
+/// Returns the number of free blocks in a free list
+fn list_len(mut node: &Option<&'static mut ListNode>) -> usize {
+    let mut len = 0;
+    while let Some(current) = node {
+        len += 1;
+        node = &current.next;
+    }
+    len
+}
+
+impl HeapStats for FixedSizeBlockAllocator {
+    fn stats(&self) -> AllocStats {
+        let mut free_blocks = [0; BLOCK_SIZES.len()];
+        for (count, head) in free_blocks.iter_mut().zip(self.list_heads.iter()) {
+            *count = list_len(head);
+        }
+
+        AllocStats {
+            free_blocks: Some(free_blocks),
+            ..self.stats
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     // Remove block allocation from list
@@ -60,11 +126,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             },
             // Block size too big, so use fallback allocator
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.stats.record_alloc(alloc_size(&layout));
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.stats.record_dealloc(alloc_size(&layout));
 
         match list_index(&layout) {
             Some(index) => {
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, AllocStats, HeapStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
 use core::ptr;
@@ -24,28 +24,62 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    stats: AllocStats,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            stats: AllocStats::new(),
         }
     }
 
+    /// Inserts a free region into the list, which is kept sorted by address, and merges it with
+    /// any directly adjacent regions
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert!(align_up(addr, mem::align_of::<ListNode>()) == addr);
         assert!(size >= mem::size_of::<ListNode>());
 
+        // Find the last region that starts before the new one
+        let mut prev = &mut self.head;
+        while prev
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = prev.next.take();
         let node_addr = addr as *mut ListNode;
         node_addr.write(node);
-        self.head.next = Some(&mut *node_addr);
+        let node = &mut *node_addr;
+
+        // Merge with the following region
+        let touches_next = node
+            .next
+            .as_ref()
+            .map_or(false, |next| node.end_addr() == next.start_addr());
+        if touches_next {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        // Merge with the preceding region. The dummy head has size 0 and never absorbs anything.
+        if prev.size > 0 && prev.end_addr() == node.start_addr() {
+            prev.size += node.size;
+            prev.next = node.next.take();
+        } else {
+            prev.next = Some(node);
+        }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.stats.bytes_free += heap_size;
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(usize, usize)> {
@@ -88,6 +122,12 @@ impl LinkedListAllocator {
     }
 }
 
+impl HeapStats for LinkedListAllocator {
+    fn stats(&self) -> AllocStats {
+        self.stats
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
@@ -99,6 +139,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             if excess > 0 {
                 allocator.add_free_region(end, excess);
             }
+            allocator.stats.record_alloc(size);
             start as *mut u8
         } else {
             ptr::null_mut()
@@ -108,6 +149,8 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size);
+        let mut allocator = self.lock();
+        allocator.add_free_region(ptr as usize, size);
+        allocator.stats.record_dealloc(size);
     }
 }
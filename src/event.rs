@@ -1,8 +1,119 @@
 pub mod keyboard;
+pub mod rtc;
 pub mod timer;
 
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Registers the IRQ handlers that feed the event sources, and the sources themselves
+///
+/// Timer events come from `time::ticks`, and RTC events are fed by `time::rtc`, which owns the RTC
+/// IRQ.
+pub fn init() {
+    keyboard::init();
+
+    register_source(&*keyboard::KEYBOARD_EVENT_DISPATCHER);
+    register_source(&*timer::TIMER_EVENT_DISPATCHER);
+    register_source(&*rtc::RTC_EVENT_DISPATCHER);
+}
+
 pub trait Listener {
     type Value;
 
     fn recv_polled_val(&mut self, polled_val: Self::Value);
 }
+
+/// Identifies a listener added to a dispatcher, so it can be removed later
+///
+/// Handles are never reused, so a stale handle can't remove a listener that was added later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ListenerHandle(u64);
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+impl ListenerHandle {
+    fn new() -> Self {
+        ListenerHandle(NEXT_HANDLE.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Something that produces events for an `EventDispatcher`, usually fed by an interrupt handler
+pub trait EventSource: Send {
+    type Event: Clone;
+
+    /// Passes every event that came in since the last poll to `emit`, in order
+    fn poll(&mut self, emit: &mut dyn FnMut(Self::Event));
+}
+
+type BoxedListener<T> = Box<dyn Listener<Value = T> + Send>;
+
+/// Sends the events of a source to every listener
+pub struct EventDispatcher<S: EventSource> {
+    source: S,
+    listeners: Vec<(ListenerHandle, BoxedListener<S::Event>)>,
+}
+
+impl<S: EventSource> EventDispatcher<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    pub fn poll(&mut self) {
+        let listeners = &mut self.listeners;
+        self.source.poll(&mut |event| {
+            for (_, listener) in listeners.iter_mut() {
+                listener.recv_polled_val(event.clone());
+            }
+        });
+    }
+
+    // Returns a handle that can be used to remove the listener
+    pub fn add_listener(&mut self, listener: BoxedListener<S::Event>) -> ListenerHandle {
+        let handle = ListenerHandle::new();
+        self.listeners.push((handle, listener));
+        handle
+    }
+
+    pub fn remove_listener(&mut self, handle: ListenerHandle) -> Option<BoxedListener<S::Event>> {
+        let index = self.listeners.iter().position(|(h, _)| *h == handle)?;
+        Some(self.listeners.remove(index).1)
+    }
+}
+
+/// A dispatcher of any event type, so dispatchers can be polled together
+pub trait Poll: Send {
+    fn poll(&mut self);
+}
+
+impl<S: EventSource> Poll for EventDispatcher<S> {
+    fn poll(&mut self) {
+        EventDispatcher::poll(self);
+    }
+}
+
+lazy_static! {
+    static ref SOURCES: Mutex<Vec<&'static Mutex<dyn Poll>>> = Mutex::new(Vec::new());
+}
+
+/// Adds a dispatcher to the ones polled by `poll_all`
+pub fn register_source(dispatcher: &'static Mutex<dyn Poll>) {
+    SOURCES.lock().push(dispatcher);
+}
+
+/// Polls every registered dispatcher once
+///
+/// Listeners must not register sources, and the caller must not hold any dispatcher's lock.
+pub fn poll_all() {
+    for dispatcher in SOURCES.lock().iter() {
+        dispatcher.lock().poll();
+    }
+}
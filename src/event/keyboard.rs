@@ -1,77 +1,205 @@
-use super::Listener;
-use alloc::{boxed::Box, vec::Vec};
+use super::{EventDispatcher, EventSource, Listener};
+use crate::interrupts::{irq, InterruptIndex};
+use crate::task::{Stream, WakerSlot};
+use core::cell::UnsafeCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+type KeyDecoder = Keyboard<layouts::Us104Key, ScancodeSet1>;
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    );
+    static ref KEYBOARD: Mutex<KeyDecoder> = Mutex::new(new_decoder());
+}
+
+fn new_decoder() -> KeyDecoder {
+    Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
 }
 
-// This lock is accessed by keyboard interrupt, so any other contention should
-// be minimized and done with interrupts disabled
-static SCANCODE: Mutex<Option<u8>> = Mutex::new(None);
+fn decode(keyboard: &mut KeyDecoder, scancode: u8) -> Option<DecodedKey> {
+    match keyboard.add_byte(scancode) {
+        Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+        _ => None,
+    }
+}
 
-pub fn update_scancode(scancode: u8) {
-    *SCANCODE.lock() = Some(scancode);
+/// Fixed-capacity lock-free queue of scancodes, filled by the keyboard interrupt
+///
+/// Only one producer and one consumer may use it at a time, which is why `push` and `pop` are
+/// unsafe. Scancodes pushed while it's full are dropped and counted.
+pub struct ScancodeQueue {
+    buffer: UnsafeCell<[u8; SCANCODE_QUEUE_SIZE]>,
+    // Next slot to pop, only written by the consumer
+    head: AtomicUsize,
+    // Next slot to push, only written by the producer
+    tail: AtomicUsize,
+    overflows: AtomicU64,
 }
 
-type KeyboardListener = Box<dyn Listener<Value = DecodedKey> + Send>;
+// Must be a power of 2, so the indices can wrap around
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+// Slots are only written by the producer before publishing them, and only read by the consumer
+// after they're published
+unsafe impl Sync for ScancodeQueue {}
+
+impl ScancodeQueue {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; SCANCODE_QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns false and counts an overflow if the queue is full
+    ///
+    /// Unsafe since no other `push` may run until this one returns, including from an interrupt
+    /// handler that preempts it. Pushing from the interrupt handler that owns the queue, or with
+    /// interrupts disabled, is enough on a single CPU.
+    pub unsafe fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == SCANCODE_QUEUE_SIZE {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        (*self.buffer.get())[tail % SCANCODE_QUEUE_SIZE] = scancode;
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Unsafe since no other `pop` may run until this one returns, so the queue needs a single
+    /// owner on the consuming side, like a dispatcher behind a lock
+    pub unsafe fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let scancode = (*self.buffer.get())[head % SCANCODE_QUEUE_SIZE];
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(scancode)
+    }
 
-pub struct KeyboardEventDispatcher {
-    listeners: Vec<KeyboardListener>,
+    /// Returns how many scancodes were dropped because the queue was full
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
 }
 
-impl KeyboardEventDispatcher {
-    pub fn poll(&mut self) {
-        let mut scancode = None;
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            scancode = SCANCODE.lock().take();
-        });
+// Filled by update_scancode and drained by KeyboardSource::poll, behind the dispatcher's lock
+static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+
+// Drained only by the one KeyStream that exists at a time
+static STREAM_SCANCODES: ScancodeQueue = ScancodeQueue::new();
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+static STREAM_WAKER: WakerSlot = WakerSlot::new();
 
-        self.poll_key(scancode);
+// Must be called with interrupts disabled, like from the keyboard interrupt, so it's the only
+// producer of the scancode queues
+pub(crate) fn update_scancode(scancode: u8) {
+    debug_assert!(!interrupts::are_enabled());
+    unsafe { SCANCODES.push(scancode) };
+    if STREAM_TAKEN.load(Ordering::Acquire) {
+        unsafe { STREAM_SCANCODES.push(scancode) };
+        STREAM_WAKER.wake();
     }
+}
+
+/// Returns how many scancodes were lost because they weren't polled in time
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.overflows()
+}
+
+pub fn init() {
+    irq::register(InterruptIndex::Keyboard.irq(), || {
+        let mut port = Port::new(0x60);
+        let scancode: u8 = unsafe { port.read() };
+        update_scancode(scancode);
+    });
+}
+
+/// Decodes the scancodes queued by the keyboard interrupt into keys
+pub struct KeyboardSource {
+    scancodes: &'static ScancodeQueue,
+}
 
-    fn poll_key(&mut self, mut scancode: Option<u8>) {
+impl EventSource for KeyboardSource {
+    type Event = DecodedKey;
+
+    fn poll(&mut self, emit: &mut dyn FnMut(DecodedKey)) {
         let mut keyboard = KEYBOARD.lock();
 
-        if let Some(scancode) = scancode.take() {
-            if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-                if let Some(key) = keyboard.process_keyevent(key_event) {
-                    for listener in &mut self.listeners {
-                        listener.recv_polled_val(key);
-                    }
-                }
+        // The dispatcher's lock makes this the only consumer
+        while let Some(scancode) = unsafe { self.scancodes.pop() } {
+            if let Some(key) = decode(&mut keyboard, scancode) {
+                emit(key);
             }
         }
     }
+}
+
+lazy_static! {
+    pub static ref KEYBOARD_EVENT_DISPATCHER: Mutex<EventDispatcher<KeyboardSource>> =
+        Mutex::new(EventDispatcher::new(KeyboardSource {
+            scancodes: &SCANCODES
+        }));
+}
 
-    // Returns a handle (listener address) that can be used to remove the listener
-    pub fn add_listener(&mut self, listener: KeyboardListener) -> u64 {
-        let handle = listener.as_ref() as *const _ as *const u64 as u64;
-        self.listeners.push(listener);
-        handle
+/// Keys pressed since the stream was taken, woken by the keyboard interrupt
+///
+/// Gets its own copy of the scancodes, so it doesn't take keys from the keyboard dispatcher.
+pub struct KeyStream {
+    keyboard: KeyDecoder,
+}
+
+impl KeyStream {
+    /// Returns None if another KeyStream exists, since only one task can wait on the keyboard
+    pub fn take() -> Option<Self> {
+        if STREAM_TAKEN
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        // Drop what was pushed for the previous stream, now that this one is the only consumer
+        while unsafe { STREAM_SCANCODES.pop() }.is_some() {}
+        Some(Self {
+            keyboard: new_decoder(),
+        })
     }
+}
 
-    pub fn remove_listener(&mut self, handle: u64) -> Option<KeyboardListener> {
-        let result = self
-            .listeners
-            .iter()
-            .map(|l| l.as_ref() as *const _ as *const u64 as u64)
-            .enumerate()
-            .find(|(_, n)| *n == handle);
+impl Stream for KeyStream {
+    type Item = DecodedKey;
 
-        result.map(|(i, _)| self.listeners.remove(i))
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        // Register before checking, so a scancode pushed right after the check still wakes us
+        STREAM_WAKER.register(cx.waker());
+        // Only one KeyStream exists at a time, so this is the only consumer
+        while let Some(scancode) = unsafe { STREAM_SCANCODES.pop() } {
+            if let Some(key) = decode(&mut self.keyboard, scancode) {
+                return Poll::Ready(Some(key));
+            }
+        }
+        Poll::Pending
     }
 }
 
-lazy_static! {
-    pub static ref KEYBOARD_EVENT_DISPATCHER: Mutex<KeyboardEventDispatcher> =
-        Mutex::new(KeyboardEventDispatcher {
-            listeners: Vec::new()
-        });
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        STREAM_WAKER.clear();
+        STREAM_TAKEN.store(false, Ordering::Release);
+    }
 }
 
 pub struct KeyPrinter;
@@ -87,9 +215,30 @@ impl Listener for KeyPrinter {
     }
 }
 
+/// Prints the global allocator's stats when F12 is pressed
+pub struct HeapStatsPrinter;
+
+impl Listener for HeapStatsPrinter {
+    type Value = DecodedKey;
+
+    fn recv_polled_val(&mut self, key: Self::Value) {
+        if key == DecodedKey::RawKey(KeyCode::F12) {
+            if let Some(stats) = crate::allocator::stats() {
+                println!("\n{}", stats);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::task::{executor::Executor, StreamExt};
+    use alloc::boxed::Box;
+
+    fn dispatcher(scancodes: &'static ScancodeQueue) -> EventDispatcher<KeyboardSource> {
+        EventDispatcher::new(KeyboardSource { scancodes })
+    }
 
     struct MockListener {
         expected: DecodedKey,
@@ -110,7 +259,8 @@ mod test {
     }
 
     test!(add_remove {
-        let mut dispatcher = KeyboardEventDispatcher { listeners: Vec::new() };
+        static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+        let mut dispatcher = dispatcher(&SCANCODES);
         let l1 = Box::new(MockListener::new(DecodedKey::Unicode('c')));
         let l2 = Box::new(MockListener::new(DecodedKey::Unicode('c')));
         let h1 = dispatcher.add_listener(l1);
@@ -124,12 +274,121 @@ mod test {
     });
 
     test!(correct_key {
-        let mut dispatcher = KeyboardEventDispatcher { listeners: Vec::new() };
+        static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+        let mut dispatcher = dispatcher(&SCANCODES);
         let mock = Box::new(MockListener::new(DecodedKey::Unicode(' ')));
         dispatcher.add_listener(mock);
         // Should do nothing
-        dispatcher.poll_key(None);
-        // Should check off the flag
-        dispatcher.poll_key(Some(57));
+        dispatcher.poll();
+        // Should check off the flag. Only this test uses the queue.
+        unsafe { SCANCODES.push(57) };
+        dispatcher.poll();
+        assert_eq!(unsafe { SCANCODES.pop() }, None);
+    });
+
+    static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    // Zero-sized, so every box of them has the same address
+    struct FirstZst;
+    struct SecondZst;
+
+    impl Listener for FirstZst {
+        type Value = DecodedKey;
+
+        fn recv_polled_val(&mut self, _: Self::Value) {
+            FIRST_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Listener for SecondZst {
+        type Value = DecodedKey;
+
+        fn recv_polled_val(&mut self, _: Self::Value) {
+            SECOND_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    test!(remove_zero_sized_listeners {
+        static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+        let mut dispatcher = dispatcher(&SCANCODES);
+        let first = dispatcher.add_listener(Box::new(FirstZst));
+        let second = dispatcher.add_listener(Box::new(SecondZst));
+        let third = dispatcher.add_listener(Box::new(FirstZst));
+        assert!(first != second && second != third && first != third);
+
+        assert!(dispatcher.remove_listener(second).is_some());
+        // Stale handles don't remove anything, even after adding more listeners
+        assert!(dispatcher.remove_listener(second).is_none());
+        dispatcher.add_listener(Box::new(FirstZst));
+        assert!(dispatcher.remove_listener(second).is_none());
+
+        unsafe { SCANCODES.push(57) };
+        dispatcher.poll();
+        assert_eq!(FIRST_CALLS.load(Ordering::Relaxed), 3);
+        assert_eq!(SECOND_CALLS.load(Ordering::Relaxed), 0);
+    });
+
+    // The queues in these tests are local, so the test is their only producer and consumer
+
+    test!(queue_is_fifo {
+        let queue = ScancodeQueue::new();
+        unsafe {
+            assert_eq!(queue.pop(), None);
+            // Go around the buffer a few times
+            for i in 0..3 * SCANCODE_QUEUE_SIZE {
+                assert!(queue.push(i as u8));
+                assert!(queue.push(!(i as u8)));
+                assert_eq!(queue.pop(), Some(i as u8));
+                assert_eq!(queue.pop(), Some(!(i as u8)));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+        assert_eq!(queue.overflows(), 0);
+    });
+
+    test!(queue_counts_overflows {
+        let queue = ScancodeQueue::new();
+        unsafe {
+            for i in 0..SCANCODE_QUEUE_SIZE {
+                assert!(queue.push(i as u8));
+            }
+            assert!(!queue.push(0xff));
+            assert!(!queue.push(0xff));
+            assert_eq!(queue.overflows(), 2);
+
+            // Nothing already queued is lost
+            for i in 0..SCANCODE_QUEUE_SIZE {
+                assert_eq!(queue.pop(), Some(i as u8));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    });
+
+    test!(key_stream_wakes_task {
+        let mut executor = Executor::new();
+        executor.spawn(async {
+            let mut keys = KeyStream::take().unwrap();
+            assert!(KeyStream::take().is_none());
+            assert_eq!(keys.next().await, Some(DecodedKey::Unicode(' ')));
+        });
+
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 1);
+        // Pretend the keyboard interrupt fired
+        interrupts::without_interrupts(|| update_scancode(57));
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 0);
+
+        // Dropping the stream frees it up
+        assert!(KeyStream::take().is_some());
+
+        // The scancode also went to the keyboard dispatcher's queue. Drain it behind the
+        // dispatcher's lock, so the test is its only consumer.
+        let _dispatcher = KEYBOARD_EVENT_DISPATCHER.lock();
+        unsafe {
+            assert_eq!(super::SCANCODES.pop(), Some(57));
+            assert_eq!(super::SCANCODES.pop(), None);
+        }
     });
 }
@@ -0,0 +1,103 @@
+use super::{EventDispatcher, EventSource, Listener};
+use crate::time::rtc::{self, RtcInterrupt, PERIODIC_INTERRUPT, UPDATE_ENDED_INTERRUPT};
+use core::sync::atomic::{AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// RTC interrupt flags from status register C that haven't been dispatched yet
+static PENDING: AtomicU8 = AtomicU8::new(0);
+
+// Should only be called by interrupt
+pub fn update(flags: u8) {
+    PENDING.fetch_or(flags, Ordering::Relaxed);
+}
+
+/// Turns the RTC interrupts into events
+pub struct RtcSource {
+    // Reported with every periodic event
+    rate: u8,
+}
+
+impl RtcSource {
+    /// Turns on an RTC interrupt, whose events get sent to the listeners
+    pub fn enable(&mut self, interrupt: RtcInterrupt) {
+        if let RtcInterrupt::Periodic { rate } = interrupt {
+            self.rate = rate;
+        }
+        rtc::enable_interrupt(interrupt);
+    }
+
+    pub fn disable(&mut self, interrupt: RtcInterrupt) {
+        rtc::disable_interrupt(interrupt);
+    }
+}
+
+impl EventSource for RtcSource {
+    type Event = RtcInterrupt;
+
+    fn poll(&mut self, emit: &mut dyn FnMut(RtcInterrupt)) {
+        let pending = PENDING.swap(0, Ordering::Relaxed);
+        if pending & PERIODIC_INTERRUPT != 0 {
+            emit(RtcInterrupt::Periodic { rate: self.rate });
+        }
+        if pending & UPDATE_ENDED_INTERRUPT != 0 {
+            emit(RtcInterrupt::UpdateEnded);
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref RTC_EVENT_DISPATCHER: Mutex<EventDispatcher<RtcSource>> =
+        Mutex::new(EventDispatcher::new(RtcSource { rate: 0 }));
+}
+
+/// Prints the date and time every time the RTC finishes an update
+pub struct ClockPrinter;
+
+impl Listener for ClockPrinter {
+    type Value = RtcInterrupt;
+
+    fn recv_polled_val(&mut self, event: Self::Value) {
+        if event == RtcInterrupt::UpdateEnded {
+            println!("\n{}", rtc::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use core::sync::atomic::AtomicUsize;
+
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+    struct MockListener;
+
+    impl Listener for MockListener {
+        type Value = RtcInterrupt;
+
+        fn recv_polled_val(&mut self, event: Self::Value) {
+            assert_eq!(event, RtcInterrupt::Periodic { rate: 6 });
+            EVENTS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    test!(periodic_interrupts {
+        let mut dispatcher = EventDispatcher::new(RtcSource { rate: 0 });
+        dispatcher.add_listener(Box::new(MockListener));
+
+        // 1024 Hz, so the timer wakes up the loop long before the last try
+        dispatcher.source().enable(RtcInterrupt::Periodic { rate: 6 });
+        for _ in 0..100 {
+            x86_64::instructions::hlt();
+            dispatcher.poll();
+            if EVENTS.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+        }
+        dispatcher.source().disable(RtcInterrupt::Periodic { rate: 6 });
+
+        assert!(EVENTS.load(Ordering::Relaxed) > 0);
+    });
+}
@@ -1,63 +1,155 @@
-use super::Listener;
-use alloc::{boxed::Box, vec::Vec};
+use super::{EventDispatcher, EventSource, Listener};
+use crate::task::{Stream, WakerSlot};
+use crate::time;
+use core::pin::Pin;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-static UNCONSUMED_TICK: AtomicBool = AtomicBool::new(false);
-
-// Should only be called by interrupt
-pub fn update_tick() {
-    UNCONSUMED_TICK.store(true, Ordering::Relaxed);
+/// Sent to timer listeners once per poll that saw new ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerEvent {
+    /// The current tick number, as counted by `time::ticks`
+    pub tick: u64,
+    /// How many ticks happened since the previous event, more than 1 if polling fell behind
+    pub elapsed: u64,
 }
 
-type TimerListener = Box<dyn Listener<Value = ()> + Send>;
-
-pub struct TimerEventDispatcher {
-    listeners: Vec<TimerListener>,
+/// Turns the ticks counted by `time` into timer events
+pub struct TimerSource {
+    // Tick number of the last event sent to the listeners
+    last_tick: u64,
 }
 
-impl TimerEventDispatcher {
-    pub fn poll(&mut self) {
-        if UNCONSUMED_TICK.compare_and_swap(true, false, Ordering::Relaxed) {
-            for listener in &mut self.listeners {
-                listener.recv_polled_val(());
-            }
+impl TimerSource {
+    fn poll_tick(&mut self, tick: u64, emit: &mut dyn FnMut(TimerEvent)) {
+        if tick > self.last_tick {
+            emit(TimerEvent {
+                tick,
+                elapsed: tick - self.last_tick,
+            });
+            self.last_tick = tick;
         }
     }
+}
 
-    // Returns a handle (listener address) that can be used to remove the listener
-    pub fn add_listener(&mut self, listener: TimerListener) -> u64 {
-        let handle = listener.as_ref() as *const _ as *const u64 as u64;
-        self.listeners.push(listener);
-        handle
+impl EventSource for TimerSource {
+    type Event = TimerEvent;
+
+    fn poll(&mut self, emit: &mut dyn FnMut(TimerEvent)) {
+        self.poll_tick(time::ticks(), emit);
     }
+}
+
+lazy_static! {
+    pub static ref TIMER_EVENT_DISPATCHER: Mutex<EventDispatcher<TimerSource>> =
+        Mutex::new(EventDispatcher::new(TimerSource { last_tick: 0 }));
+}
+
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+static STREAM_WAKER: WakerSlot = WakerSlot::new();
+
+// Should only be called by interrupt
+pub fn update() {
+    STREAM_WAKER.wake();
+}
 
-    pub fn remove_listener(&mut self, handle: u64) -> Option<TimerListener> {
-        let result = self
-            .listeners
-            .iter()
-            .map(|l| l.as_ref() as *const _ as *const u64 as u64)
-            .enumerate()
-            .find(|(_, n)| *n == handle);
+/// Timer events since the stream was taken, woken by the timer interrupt
+pub struct TickStream {
+    source: TimerSource,
+}
 
-        result.map(|(i, _)| self.listeners.remove(i))
+impl TickStream {
+    /// Returns None if another TickStream exists, since only one task can wait on the timer
+    pub fn take() -> Option<Self> {
+        if STREAM_TAKEN
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        Some(Self {
+            source: TimerSource {
+                last_tick: time::ticks(),
+            },
+        })
     }
 }
 
-lazy_static! {
-    pub static ref TIMER_EVENT_DISPATCHER: Mutex<TimerEventDispatcher> =
-        Mutex::new(TimerEventDispatcher {
-            listeners: Vec::new()
-        });
+impl Stream for TickStream {
+    type Item = TimerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<TimerEvent>> {
+        // Register before checking, so a tick right after the check still wakes us
+        STREAM_WAKER.register(cx.waker());
+        let mut next = None;
+        self.source.poll(&mut |event| next = Some(event));
+        match next {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for TickStream {
+    fn drop(&mut self) {
+        STREAM_WAKER.clear();
+        STREAM_TAKEN.store(false, Ordering::Release);
+    }
 }
 
 pub struct TimerPrinter;
 
 impl Listener for TimerPrinter {
-    type Value = ();
+    type Value = TimerEvent;
 
     fn recv_polled_val(&mut self, _: Self::Value) {
         print!(":)");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{executor::Executor, StreamExt};
+    use alloc::vec::Vec;
+
+    test!(counts_missed_ticks {
+        let mut events = Vec::new();
+        let mut source = TimerSource { last_tick: 10 };
+        let mut emit = |event| events.push(event);
+
+        source.poll_tick(10, &mut emit);
+        source.poll_tick(11, &mut emit);
+        // Three ticks came in before the next poll
+        source.poll_tick(14, &mut emit);
+        source.poll_tick(14, &mut emit);
+
+        let expected = [
+            TimerEvent { tick: 11, elapsed: 1 },
+            TimerEvent { tick: 14, elapsed: 3 },
+        ];
+        assert_eq!(&events[..], &expected[..]);
+    });
+
+    test!(tick_stream_wakes_task {
+        static DONE: AtomicBool = AtomicBool::new(false);
+        let mut executor = Executor::new();
+        executor.spawn(async {
+            let mut ticks = TickStream::take().unwrap();
+            assert!(TickStream::take().is_none());
+            let first = ticks.next().await.unwrap();
+            let second = ticks.next().await.unwrap();
+            assert_eq!(second.tick, first.tick + second.elapsed);
+            DONE.store(true, Ordering::Relaxed);
+        });
+
+        while !DONE.load(Ordering::Relaxed) {
+            executor.run_ready_tasks();
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(executor.task_count(), 0);
+        assert!(TickStream::take().is_some());
+    });
+}
@@ -1,29 +1,87 @@
+use crate::memory::{self, MapError};
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
+use spin::Once;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+const IST_STACK_SIZE: u64 = 16 * 1024;
+// Tops of the NMI, machine check and page fault stacks, in IST index order
+static IST_STACKS: Once<[VirtAddr; 3]> = Once::new();
+
+// The double fault stack is static so it works even before memory is set up
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+
+#[repr(align(16))]
+struct Stack(UnsafeCell<[u8; DOUBLE_FAULT_STACK_SIZE]>);
+
+// Only ever touched by the CPU, as the double fault handler's stack
+unsafe impl Sync for Stack {}
+
+static DOUBLE_FAULT_STACK: Stack = Stack(UnsafeCell::new([0; DOUBLE_FAULT_STACK_SIZE]));
+
+/// Returns the bottom and top of the double fault handler's stack
+pub fn double_fault_stack() -> (VirtAddr, VirtAddr) {
+    let stack_bottom = VirtAddr::from_ptr(DOUBLE_FAULT_STACK.0.get());
+    (stack_bottom, stack_bottom + DOUBLE_FAULT_STACK_SIZE)
+}
+
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Only written by `init_ist_stacks`, before any IDT entry uses the stacks it adds
+unsafe impl Sync for Tss {}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_bottom = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_bottom + STACK_SIZE
-        };
-        tss
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack().1;
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+/// Allocates guarded stacks for the NMI, machine check and page fault handlers, and adds them to
+/// the TSS
+///
+/// Can run before or after `init`, since the CPU only reads the TSS when switching stacks.
+/// `interrupts::init_idt` needs the stacks, so until this runs only `interrupts::init_early_idt`
+/// can be used, which leaves those handlers on the stack of whatever they interrupted.
+pub fn init_ist_stacks<A>(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MapError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let mut stacks = [VirtAddr::new(0); 3];
+    for stack in stacks.iter_mut() {
+        *stack = memory::allocate_stack(IST_STACK_SIZE, mapper, frame_allocator)?;
+    }
+
+    let stacks = IST_STACKS.call_once(|| stacks);
+    let first = NMI_IST_INDEX as usize;
+    unsafe {
+        let tss = &mut *TSS.0.get();
+        tss.interrupt_stack_table[first..first + stacks.len()].copy_from_slice(stacks);
+    }
+    Ok(())
+}
+
+pub fn has_ist_stacks() -> bool {
+    IST_STACKS.r#try().is_some()
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (gdt, code_selector, tss_selector)
     };
 }
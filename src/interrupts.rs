@@ -1,6 +1,12 @@
-use crate::event;
+pub mod apic;
+mod exceptions;
+pub mod irq;
+mod page_fault;
+
 use crate::gdt;
+use crate::memory;
 use lazy_static::lazy_static;
+use page_fault::PageFaultReport;
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
@@ -19,25 +25,78 @@ pub enum InterruptIndex {
     Keyboard,
 }
 
-pub fn init_pics() {
+impl InterruptIndex {
+    /// The legacy IRQ line raising this interrupt
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC1_OFFSET
+    }
+}
+
+/// Sets up the interrupt controllers and enables interrupts
+///
+/// IRQs go through the local APIC and I/O APICs if the CPU has them and the ACPI MADT describes
+/// them, and through the legacy PICs otherwise. With the APICs, lines stay masked until a handler
+/// is registered with `irq`. Needs `memory::MAPPER` and `memory::FRAME_ALLOCATOR` for mapping the
+/// APICs.
+pub fn init_controller() {
+    // Remap the PICs even if they end up masked, so their spurious IRQs can't look like exceptions
     unsafe { PICS.lock().initialize() };
+
+    if let Err(err) = apic::init() {
+        serial_println!("Using the legacy PICs: {:?}", err);
+    }
+
     x86_64::instructions::interrupts::enable();
 }
 
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(irq::vector(irq)) };
+    }
+}
+
+// Every exception handler, with the NMI, machine check and page fault handlers on their own IST
+// stacks if `ist_stacks` is set
+fn exception_idt(ist_stacks: bool) -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+
+    exceptions::set_handlers(&mut idt, ist_stacks);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    let page_fault = idt.page_fault.set_handler_fn(page_fault_handler);
+    if ist_stacks {
+        unsafe { page_fault.set_stack_index(gdt::PAGE_FAULT_IST_INDEX) };
+    }
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+
+    idt
+}
+
 lazy_static! {
+    static ref EARLY_IDT: InterruptDescriptorTable = exception_idt(false);
     static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        assert!(
+            gdt::has_ist_stacks(),
+            "the IDT needs the IST stacks from gdt::init_ist_stacks"
+        );
+        let mut idt = exception_idt(true);
+
+        for (line, &trampoline) in irq::TRAMPOLINES.iter().enumerate() {
+            idt[irq::vector(line as u8) as usize].set_handler_fn(trampoline);
         }
-
-        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_interrupt_handler);
 
         idt
     };
@@ -49,11 +108,17 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFra
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut InterruptStackFrame,
-    _err: PageFaultErrorCode,
+    err: PageFaultErrorCode,
 ) {
+    let addr = Cr2::read();
+    if memory::handle_page_fault(addr, err) {
+        return;
+    }
+
     panic!(
-        "Exception: Page Fault at {:?}:\n{:#?}",
-        Cr2::read(),
+        "Exception: Page Fault at {:?}\n{}{:#?}",
+        addr,
+        PageFaultReport { addr, error: err },
         stack_frame
     );
 }
@@ -65,26 +130,15 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("Exception: Double Fault:\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    event::timer::update_tick();
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8)
-    };
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    event::keyboard::update_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8)
-    };
+/// Loads an IDT that reports exceptions, for before memory is set up
+///
+/// Only needs `gdt::init`. Has no IRQ handlers, and runs every handler except the double fault
+/// one on the interrupted stack.
+pub fn init_early_idt() {
+    EARLY_IDT.load();
 }
 
+/// Loads the full IDT, which needs `gdt::init` and `gdt::init_ist_stacks`
 pub fn init_idt() {
     IDT.load();
 }
@@ -0,0 +1,266 @@
+use crate::acpi::{IsaIrq, Madt};
+use crate::memory;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APIC registers, as byte offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LVT_MASKED: u32 = 1 << 16;
+
+// I/O APIC registers are accessed indirectly, by writing the index to IOREGSEL
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Vector the local APIC raises for spurious interrupts, which must not get an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+struct Apic {
+    local: VirtAddr,
+    io_apics: Vec<IoApic>,
+    isa_irqs: [IsaIrq; 16],
+}
+
+static APIC: Once<Apic> = Once::new();
+
+impl Apic {
+    fn read_local(&self, reg: usize) -> u32 {
+        unsafe { (self.local + reg).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write_local(&self, reg: usize, value: u32) {
+        unsafe { (self.local + reg).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let io_apic = self
+            .io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .unwrap_or_else(|| panic!("no I/O APIC handles GSI {}", gsi));
+        io_apic.set_redirection(gsi - io_apic.gsi_base, entry);
+    }
+}
+
+// Unmapped again when dropped, which only happens when `init` fails
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_count: u32,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC at `address`, checking that it's really there
+    fn new(address: PhysAddr, gsi_base: u32) -> Result<Self, ApicError> {
+        let base = memory::map_mmio(address, 0x20).map_err(|_| ApicError::MappingFailed)?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            redirection_count: 0,
+        };
+
+        // Nothing at the address reads as all ones, and real I/O APICs are version 0x1X or 0x20
+        let version = io_apic.read(IOAPIC_VERSION);
+        if version == u32::max_value() || !(0x10..=0x20).contains(&(version & 0xff)) {
+            return Err(ApicError::BadIoApic(address));
+        }
+        io_apic.redirection_count = ((version >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi - self.gsi_base < self.redirection_count
+    }
+
+    fn set_redirection(&self, input: u32, entry: u64) {
+        let reg = IOREDTBL + 2 * input;
+        self.write(reg, entry as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+    }
+}
+
+impl Drop for IoApic {
+    fn drop(&mut self) {
+        memory::unmap_mmio(self.base);
+    }
+}
+
+/// Why the APICs can't be used, in which case the legacy PICs stay in charge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    NotSupported,
+    /// There's no valid ACPI MADT listing the I/O APICs
+    NoMadt,
+    NoIoApic,
+    /// The MADT lists an I/O APIC that doesn't respond like one
+    BadIoApic(PhysAddr),
+    MappingFailed,
+}
+
+/// Checks CPUID for an on-chip local APIC
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Whether interrupts are delivered through the APICs instead of the legacy PICs
+pub fn is_enabled() -> bool {
+    APIC.r#try().is_some()
+}
+
+/// Finds the APICs in the ACPI MADT, then masks the legacy PICs, enables the local APIC and masks
+/// every I/O APIC line, as well as the local APIC's LINT0 input the PICs are wired to
+///
+/// Leaves everything alone if the APICs can't be used. Needs `memory::MAPPER` and
+/// `memory::FRAME_ALLOCATOR` to be set up. Lines are unmasked with `route_irq`.
+pub fn init() -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = Madt::parse().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+    // If one fails, the ones mapped before it are dropped and unmapped again
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|io_apic| IoApic::new(io_apic.address, io_apic.gsi_base))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The MSR has the address the local APIC actually answers at
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    let local = memory::map_mmio(PhysAddr::new(base & APIC_BASE_ADDR_MASK), 0x400)
+        .map_err(|_| ApicError::MappingFailed)?;
+
+    super::disable_pics();
+    unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+    let apic = Apic {
+        local,
+        io_apics,
+        isa_irqs: madt.isa_irqs,
+    };
+    for io_apic in &apic.io_apics {
+        for input in 0..io_apic.redirection_count {
+            io_apic.set_redirection(input, REDIRECTION_MASKED);
+        }
+    }
+    // In virtual wire mode the PICs reach the CPU as ExtINT through LINT0, even with their lines
+    // masked, and their spurious IRQs 7 and 15 would look like real I/O APIC interrupts
+    apic.write_local(LAPIC_LVT_LINT0, LVT_MASKED);
+    apic.write_local(
+        LAPIC_SPURIOUS,
+        LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+
+    APIC.call_once(|| apic);
+    Ok(())
+}
+
+/// Delivers a legacy ISA IRQ line to the current CPU at `vector`
+///
+/// The line goes to the I/O APIC input, polarity and trigger mode given by the MADT's interrupt
+/// source overrides.
+pub fn route_irq(irq: u8, vector: u8) {
+    let apic = APIC.r#try().expect("APIC isn't initialized");
+    let isa_irq = apic.isa_irqs[irq as usize];
+    let destination = (apic.read_local(LAPIC_ID) >> 24) as u64;
+
+    // Fixed delivery to a physical destination
+    let mut entry = vector as u64 | destination << 56;
+    if isa_irq.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if isa_irq.level_triggered {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    apic.set_redirection(isa_irq.gsi, entry);
+}
+
+/// Stops delivering a legacy ISA IRQ line
+pub fn mask_irq(irq: u8) {
+    let apic = APIC.r#try().expect("APIC isn't initialized");
+    apic.set_redirection(apic.isa_irqs[irq as usize].gsi, REDIRECTION_MASKED);
+}
+
+#[cfg(test)]
+pub(super) fn is_masked(irq: u8) -> bool {
+    let apic = APIC.r#try().expect("APIC isn't initialized");
+    let gsi = apic.isa_irqs[irq as usize].gsi;
+    let io_apic = apic
+        .io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .unwrap();
+    let entry = io_apic.read(IOREDTBL + 2 * (gsi - io_apic.gsi_base));
+    u64::from(entry) & REDIRECTION_MASKED != 0
+}
+
+pub fn end_of_interrupt() {
+    if let Some(apic) = APIC.r#try() {
+        apic.write_local(LAPIC_EOI, 0);
+    }
+}
+
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+    super::irq::record_spurious();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LAPIC_VERSION: usize = 0x30;
+
+    test!(local_apic_is_integrated {
+        // QEMU has APICs, so the kernel must be using them
+        assert!(is_enabled());
+        let apic = APIC.r#try().unwrap();
+
+        // Versions below 0x10 are the old external 82489DX
+        let version = apic.read_local(LAPIC_VERSION) & 0xff;
+        assert!(version >= 0x10);
+        assert!(apic.io_apics[0].redirection_count >= 16);
+        assert_eq!(apic.isa_irqs[0].gsi, 2);
+        // The PICs can't deliver anything, spurious IRQs included
+        assert!(apic.read_local(LAPIC_LVT_LINT0) & LVT_MASKED != 0);
+    });
+}
@@ -0,0 +1,144 @@
+use crate::gdt;
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+// Defines a handler that panics with the exception's name, error code and stack frame
+macro_rules! exception_handler {
+    ($name:ident, $description:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame) {
+            panic!("Exception: {}:\n{:#?}", $description, stack_frame);
+        }
+    };
+    ($name:ident, $description:expr, selector) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut InterruptStackFrame, err: u64) {
+            panic!(
+                "Exception: {} ({}):\n{:#?}",
+                $description,
+                SelectorErrorCode(err),
+                stack_frame
+            );
+        }
+    };
+}
+
+exception_handler!(divide_error_handler, "Divide Error");
+exception_handler!(debug_handler, "Debug");
+exception_handler!(non_maskable_interrupt_handler, "Non-Maskable Interrupt");
+exception_handler!(overflow_handler, "Overflow");
+exception_handler!(bound_range_exceeded_handler, "Bound Range Exceeded");
+exception_handler!(invalid_opcode_handler, "Invalid Opcode");
+exception_handler!(device_not_available_handler, "Device Not Available");
+exception_handler!(invalid_tss_handler, "Invalid TSS", selector);
+exception_handler!(segment_not_present_handler, "Segment Not Present", selector);
+exception_handler!(stack_segment_fault_handler, "Stack-Segment Fault", selector);
+exception_handler!(
+    general_protection_fault_handler,
+    "General Protection Fault",
+    selector
+);
+exception_handler!(x87_floating_point_handler, "x87 Floating-Point Exception");
+exception_handler!(simd_floating_point_handler, "SIMD Floating-Point Exception");
+exception_handler!(virtualization_handler, "Virtualization Exception");
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: &mut InterruptStackFrame, err: u64) {
+    panic!(
+        "Exception: Alignment Check (error code {:#x}):\n{:#?}",
+        err, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    panic!("Exception: Machine Check:\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: &mut InterruptStackFrame,
+    err: u64,
+) {
+    panic!(
+        "Exception: Security Exception (error code {:#x}):\n{:#?}",
+        err, stack_frame
+    );
+}
+
+/// Installs a reporting handler for every architectural exception that doesn't have a handler of
+/// its own in `interrupts`
+///
+/// The NMI and machine check handlers get their IST stacks if `ist_stacks` is set.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable, ist_stacks: bool) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    let nmi = idt
+        .non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    if ist_stacks {
+        unsafe { nmi.set_stack_index(gdt::NMI_IST_INDEX) };
+    }
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    let machine_check = idt.machine_check.set_handler_fn(machine_check_handler);
+    if ist_stacks {
+        unsafe { machine_check.set_stack_index(gdt::MACHINE_CHECK_IST_INDEX) };
+    }
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// Error code of the exceptions caused by loading a segment selector or a gate
+///
+/// Zero means the exception wasn't caused by a particular selector.
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        write!(f, "error code {:#x}", code)?;
+        if code == 0 {
+            return Ok(());
+        }
+
+        let table = match (code >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, ": {} index {}", table, (code >> 3) & 0x1fff)?;
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    test!(decode_selector_error_code {
+        assert_eq!(format!("{}", SelectorErrorCode(0)), "error code 0x0");
+        assert_eq!(format!("{}", SelectorErrorCode(0x10)), "error code 0x10: GDT index 2");
+        assert_eq!(
+            format!("{}", SelectorErrorCode((13 << 3) | 0b011)),
+            "error code 0x6b: IDT index 13, external event"
+        );
+        assert_eq!(format!("{}", SelectorErrorCode(0b100)), "error code 0x4: LDT index 0");
+    });
+}
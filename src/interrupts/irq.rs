@@ -0,0 +1,250 @@
+use super::{apic, PIC1_OFFSET};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+/// Number of legacy IRQ lines, across both PICs
+pub const IRQ_COUNT: u8 = 16;
+
+pub type IrqHandler = Box<dyn FnMut() + Send>;
+
+// OCW3 command making the next read of a PIC's command port return its in-service register
+const PIC_READ_ISR: u8 = 0x0b;
+
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+// Only locked with interrupts disabled, so the trampolines can't deadlock on them
+static HANDLERS: [Mutex<Option<IrqHandler>>; IRQ_COUNT as usize] = [
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+];
+
+// Defines one trampoline per IRQ line that dispatches to the registered handler
+macro_rules! trampolines {
+    ($($irq:expr => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// IDT entries for the IRQ vectors, in IRQ order
+        pub(super) static TRAMPOLINES: [HandlerFunc; IRQ_COUNT as usize] = [$($name),*];
+    };
+}
+
+trampolines!(
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3, 4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11, 12 => irq12, 13 => irq13, 14 => irq14,
+    15 => irq15
+);
+
+fn dispatch(irq: u8) {
+    let spurious = !apic::is_enabled() && is_spurious(irq);
+    dispatch_with(irq, spurious, super::end_of_interrupt);
+}
+
+// Split out of `dispatch` so tests can force the PIC path and see the EOIs
+fn dispatch_with(irq: u8, spurious: bool, mut end_of_interrupt: impl FnMut(u8)) {
+    if spurious {
+        record_spurious();
+        // The primary PIC did see its cascade line go up for a spurious IRQ 15, so it still needs
+        // an EOI. Spurious IRQs mustn't get one otherwise, since it could end a real interrupt.
+        if irq == 15 {
+            end_of_interrupt(2);
+        }
+        return;
+    }
+
+    if let Some(handler) = HANDLERS[irq as usize].lock().as_mut() {
+        handler();
+    }
+    end_of_interrupt(irq);
+}
+
+/// Checks whether an IRQ from the legacy PICs went away before it could be serviced
+///
+/// A PIC reports such interrupts as its lowest priority line, IRQ 7 or 15, without setting the
+/// line's bit in its in-service register.
+fn is_spurious(irq: u8) -> bool {
+    let command = match irq {
+        7 => 0x20,
+        15 => 0xa0,
+        _ => return false,
+    };
+
+    let mut port = Port::<u8>::new(command);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read() & (1 << 7) == 0
+    }
+}
+
+pub(super) fn record_spurious() {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many spurious interrupts were ignored, from the legacy PICs or the local APIC
+pub fn spurious_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Returns the vector an IRQ line is delivered at
+pub fn vector(irq: u8) -> u8 {
+    PIC1_OFFSET + irq
+}
+
+/// Runs `handler` every time IRQ line `irq` fires, returning the handler it replaces
+///
+/// The handler runs with interrupts disabled, so it must not block on anything that's held with
+/// interrupts enabled. The EOI is sent after it returns. The line is unmasked on registration.
+pub fn register<F>(irq: u8, handler: F) -> Option<IrqHandler>
+where
+    F: FnMut() + Send + 'static,
+{
+    assert!(irq < IRQ_COUNT, "IRQ {} doesn't exist", irq);
+    let handler: IrqHandler = Box::new(handler);
+
+    let previous =
+        interrupts::without_interrupts(|| HANDLERS[irq as usize].lock().replace(handler));
+    unmask(irq);
+    previous
+}
+
+/// Removes the handler of IRQ line `irq`, and masks the line again
+pub fn unregister(irq: u8) -> Option<IrqHandler> {
+    assert!(irq < IRQ_COUNT, "IRQ {} doesn't exist", irq);
+    let previous = interrupts::without_interrupts(|| HANDLERS[irq as usize].lock().take());
+    mask(irq);
+    previous
+}
+
+// Returns the mask port of the PIC with the line, and the line's bit in it
+fn pic_mask_bit(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (0x21, 1 << irq)
+    } else {
+        (0xa1, 1 << (irq - 8))
+    }
+}
+
+fn unmask(irq: u8) {
+    if apic::is_enabled() {
+        apic::route_irq(irq, vector(irq));
+        return;
+    }
+
+    // Lines of the secondary PIC also need the cascade line on the primary one
+    let (port, bit) = pic_mask_bit(irq);
+    interrupts::without_interrupts(|| unsafe {
+        let mut mask = Port::<u8>::new(port);
+        let value = mask.read();
+        mask.write(value & !bit);
+        if irq >= 8 {
+            let mut primary = Port::<u8>::new(0x21);
+            let value = primary.read();
+            primary.write(value & !(1 << 2));
+        }
+    });
+}
+
+// The cascade line stays unmasked, since other secondary lines may still need it
+fn mask(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_irq(irq);
+        return;
+    }
+
+    let (port, bit) = pic_mask_bit(irq);
+    interrupts::without_interrupts(|| unsafe {
+        let mut mask = Port::<u8>::new(port);
+        let value = mask.read();
+        mask.write(value | bit);
+    });
+}
+
+// Whether the line is masked on whichever controller is in use
+#[cfg(test)]
+fn is_masked(irq: u8) -> bool {
+    if apic::is_enabled() {
+        return apic::is_masked(irq);
+    }
+
+    let (port, bit) = pic_mask_bit(irq);
+    unsafe { Port::<u8>::new(port).read() & bit != 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // Nothing in the kernel uses the second parallel port
+    const UNUSED_IRQ: u8 = 5;
+
+    test!(register_dispatch_unregister {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        assert!(register(UNUSED_IRQ, || {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        })
+        .is_none());
+
+        interrupts::without_interrupts(|| dispatch(UNUSED_IRQ));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+        assert!(!is_masked(UNUSED_IRQ));
+        assert!(unregister(UNUSED_IRQ).is_some());
+        assert!(is_masked(UNUSED_IRQ));
+        interrupts::without_interrupts(|| dispatch(UNUSED_IRQ));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(unregister(UNUSED_IRQ).is_none());
+    });
+
+    test!(detect_spurious_irqs {
+        // Outside of an interrupt handler, nothing is in service
+        interrupts::without_interrupts(|| {
+            assert!(is_spurious(7));
+            assert!(is_spurious(15));
+            assert!(!is_spurious(UNUSED_IRQ));
+        });
+    });
+
+    test!(spurious_pic_irqs_get_the_right_eoi {
+        interrupts::without_interrupts(|| {
+            let count = spurious_count();
+            let mut eois = Vec::new();
+
+            // The PIC path, whichever controller is in use, with nothing in service
+            dispatch_with(7, is_spurious(7), |irq| eois.push(irq));
+            assert!(eois.is_empty());
+            // IRQ 2 is the cascade line, so only the primary PIC gets an EOI
+            dispatch_with(15, is_spurious(15), |irq| eois.push(irq));
+            assert_eq!(eois, [2]);
+            assert!(vector(2) < super::super::PIC2_OFFSET);
+            assert_eq!(spurious_count(), count + 2);
+
+            // Real interrupts aren't counted, and get their own EOI
+            dispatch_with(UNUSED_IRQ, false, |irq| eois.push(irq));
+            assert_eq!(eois, [2, UNUSED_IRQ]);
+            assert_eq!(spurious_count(), count + 2);
+        });
+    });
+}
@@ -0,0 +1,140 @@
+use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use crate::gdt;
+use crate::memory::{
+    self,
+    vma::{RegionKind, ADDRESS_SPACE},
+};
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{PageSize, PageTable, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// The VGA text buffer is identity mapped
+const VGA_BUFFER_START: u64 = 0xb8000;
+const VGA_BUFFER_END: u64 = VGA_BUFFER_START + 80 * 25 * 2;
+
+/// Describes an unrecoverable page fault: its cause, the region the address is in and the page
+/// table entries leading to it
+///
+/// Only reads global state with `try_lock`, since the faulting code may hold any lock.
+pub struct PageFaultReport {
+    pub addr: VirtAddr,
+    pub error: PageFaultErrorCode,
+}
+
+impl fmt::Display for PageFaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cause: ")?;
+        write_cause(f, self.error)?;
+        write!(f, "\nRegion: ")?;
+        write_region(f, self.addr)?;
+        writeln!(f)?;
+        write_table_walk(f, self.addr)
+    }
+}
+
+fn write_cause(f: &mut fmt::Formatter, error: PageFaultErrorCode) -> fmt::Result {
+    let access = if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error.contains(PageFaultErrorCode::USER_MODE) {
+        "user mode"
+    } else {
+        "kernel mode"
+    };
+    let reason = if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+
+    write!(f, "{}, {}, {}", access, mode, reason)?;
+    if error.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        write!(f, ", reserved bit set in page table")?;
+    }
+    Ok(())
+}
+
+fn write_region(f: &mut fmt::Formatter, addr: VirtAddr) -> fmt::Result {
+    let raw = addr.as_u64();
+    let heap_start = HEAP_START as u64;
+    let (double_fault_bottom, double_fault_top) = gdt::double_fault_stack();
+
+    if raw < Size4KiB::SIZE {
+        write!(f, "null page")
+    } else if VGA_BUFFER_START <= raw && raw < VGA_BUFFER_END {
+        write!(f, "VGA text buffer")
+    } else if double_fault_bottom <= addr && addr < double_fault_top {
+        write!(f, "double fault IST stack")
+    } else if heap_start <= raw && raw < heap_start + HEAP_MAX_SIZE as u64 {
+        write!(f, "heap")
+    } else {
+        let space = match ADDRESS_SPACE.try_lock() {
+            Some(space) => space,
+            None => return write!(f, "unknown, address space is locked"),
+        };
+
+        if let Some(region) = space.find(addr) {
+            // Kernel stacks keep their guard page at the bottom of the region
+            if region.kind == RegionKind::KernelStack && addr < region.start + Size4KiB::SIZE {
+                return write!(f, "guard page of KernelStack region {:?}", region.start);
+            }
+            write!(
+                f,
+                "{:?} region {:?}..{:?}",
+                region.kind,
+                region.start,
+                region.end()
+            )
+        } else {
+            match space.find(addr - Size4KiB::SIZE) {
+                Some(region) if region.end() <= addr => {
+                    write!(f, "guard page after {:?} region", region.kind)
+                }
+                _ => write!(f, "unreserved"),
+            }
+        }
+    }
+}
+
+fn write_table_walk(f: &mut fmt::Formatter, addr: VirtAddr) -> fmt::Result {
+    let offset = match memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => return writeln!(f, "Page tables: physical memory isn't mapped yet"),
+    };
+
+    let (level_4_frame, _) = Cr3::read();
+    let mut table_addr = level_4_frame.start_address();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    for (level, &index) in (1..=4).rev().zip(indices.iter()) {
+        let table_ptr: *const PageTable = (offset + table_addr.as_u64()).as_ptr();
+        let entry = unsafe { &(*table_ptr)[index] };
+        let flags = entry.flags();
+        writeln!(
+            f,
+            "P{}[{}]: {:?} {:?}",
+            level,
+            u16::from(index),
+            entry.addr(),
+            flags
+        )?;
+
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table_addr = entry.addr();
+    }
+
+    Ok(())
+}
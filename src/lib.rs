@@ -17,19 +17,19 @@ pub mod serial;
 pub mod testing;
 #[macro_use]
 pub mod vga_buffer;
+pub mod acpi;
 pub mod allocator;
 pub mod event;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod task;
+pub mod time;
 
 use bootloader::BootInfo;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::{
-    structures::paging::{FrameAllocator, MapperAllSizes, Size4KiB},
-    VirtAddr,
-};
+use x86_64::VirtAddr;
 
 pub use testing::*;
 
@@ -44,26 +44,40 @@ lazy_static! {
     static ref INIT_FLAG: Mutex<bool> = Mutex::new(false);
 }
 
-pub fn init(
-    boot_info: &'static BootInfo,
-) -> Result<(impl MapperAllSizes, impl FrameAllocator<Size4KiB>), ()> {
+// The mapper and frame allocator end up in `memory::MAPPER` and `memory::FRAME_ALLOCATOR`
+pub fn init(boot_info: &'static BootInfo) -> Result<(), ()> {
     if *INIT_FLAG.lock() {
         Err(())
     } else {
         *INIT_FLAG.lock() = true;
 
+        // Report faults from the start, then set up memory, which the other IST stacks and the
+        // full IDT need
         gdt::init();
-        interrupts::init_idt();
-        interrupts::init_pics();
+        interrupts::init_early_idt();
 
         let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
         let mut mapper = unsafe { memory::init(physical_memory_offset) };
-        let mut frame_allocator =
-            unsafe { memory::BootInfoFrameAllocator::new(&boot_info.memory_map) };
+        let mut frame_allocator = unsafe {
+            memory::BootInfoFrameAllocator::new(&boot_info.memory_map, physical_memory_offset)
+        };
+        memory::vma::init(physical_memory_offset, &boot_info.memory_map);
+        gdt::init_ist_stacks(&mut mapper, &mut frame_allocator)
+            .expect("interrupt stack allocation failed");
+        interrupts::init_idt();
+
         allocator::init_heap(&mut mapper, &mut frame_allocator)
             .expect("heap initialization failed");
 
-        Ok((mapper, frame_allocator))
+        *memory::MAPPER.lock() = Some(mapper);
+        *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+        interrupts::init_controller();
+        event::init();
+        time::init(time::DEFAULT_TICK_RATE);
+        time::tsc::init();
+
+        Ok(())
     }
 }
 
@@ -15,6 +15,9 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    if let Some(stats) = blog_os::allocator::stats() {
+        println!("{}", stats);
+    }
 
     blog_os::hlt_loop();
 }
@@ -46,19 +49,54 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 fn event_main() -> ! {
     use alloc::boxed::Box;
-    use blog_os::event::{keyboard, timer};
-
-    let keyprinter = keyboard::KeyPrinter {};
-    let mut keyrunner = keyboard::KEYBOARD_EVENT_DISPATCHER.lock();
-    let timeprinter = timer::TimerPrinter {};
-    let mut timerunner = timer::TIMER_EVENT_DISPATCHER.lock();
-
-    keyrunner.add_listener(Box::new(keyprinter));
-    timerunner.add_listener(Box::new(timeprinter));
-    loop {
-        keyrunner.poll();
-        timerunner.poll();
-        // Need this instruction to prevent tight polling from starving the interrupts
-        x86_64::instructions::hlt();
+    use blog_os::event::{keyboard, rtc, timer};
+    use blog_os::task::executor::Executor;
+    use blog_os::time::rtc::RtcInterrupt;
+
+    {
+        keyboard::KEYBOARD_EVENT_DISPATCHER
+            .lock()
+            .add_listener(Box::new(keyboard::HeapStatsPrinter));
+        timer::TIMER_EVENT_DISPATCHER
+            .lock()
+            .add_listener(Box::new(timer::TimerPrinter));
+
+        // Show the wall clock time once a second
+        let mut clock = rtc::RTC_EVENT_DISPATCHER.lock();
+        clock.add_listener(Box::new(rtc::ClockPrinter));
+        clock.source().enable(RtcInterrupt::UpdateEnded);
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(print_keys());
+    executor.spawn(run_listeners());
+    executor.run();
+}
+
+#[cfg(not(test))]
+async fn print_keys() {
+    use blog_os::event::keyboard::KeyStream;
+    use blog_os::print;
+    use blog_os::task::StreamExt;
+    use pc_keyboard::DecodedKey;
+
+    let mut keys = KeyStream::take().expect("key stream already taken");
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode(ch) => print!("{}", ch),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
+
+// Listeners still get their events, polled once per timer tick
+#[cfg(not(test))]
+async fn run_listeners() {
+    use blog_os::event::{self, timer::TickStream};
+    use blog_os::task::StreamExt;
+
+    let mut ticks = TickStream::take().expect("tick stream already taken");
+    while ticks.next().await.is_some() {
+        event::poll_all();
     }
 }
@@ -1,10 +1,32 @@
+pub mod vma;
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use spin::{Mutex, Once};
+use vma::{Backing, RegionKind, ADDRESS_SPACE};
+use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
-    UnusedPhysFrame,
+    mapper::{FlagUpdateError, MapToError, UnmapError},
+    page::PageRange,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, UnusedPhysFrame,
 };
 use x86_64::{registers::control::Cr3, PhysAddr, VirtAddr};
 
+// The kernel's page table mapper and frame allocator, handed over by `crate::init` once memory
+// is set up so that later subsystems (like heap growth) can map pages. Lock MAPPER before
+// FRAME_ALLOCATOR when both are needed, and never allocate on the heap while holding either.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Returns where the bootloader mapped all of physical memory, once `init` has run
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.r#try().copied()
+}
+
 // Physical memory must be mapped at offset.
 // Must only be called once to avoid aliasing &mut PageTable.
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -19,43 +41,450 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 // Complete physical memory must be mapped at the offset.
 // Must only be called once to avoid aliasing &mut PageTables.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Hands out usable frames from the bootloader's memory map in O(1)
+///
+/// Frames that were never handed out are taken in order from the current usable region. Freed
+/// frames go on a stack that's threaded through the frames themselves via the physical memory
+/// mapping, and are reused first. Huge frames are always carved out of never-allocated memory,
+/// and freeing one puts all of its 4KiB frames on the stack.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    physical_memory_offset: VirtAddr,
+    // Index of the memory region that new frames are taken from
+    region: usize,
+    // Next never-allocated frame address in that region
+    next: u64,
+    // Top of the stack of freed frames
+    free_stack: Option<PhysFrame>,
 }
 
+// Stored in a freed frame to mark the bottom of the free stack
+const FREE_STACK_END: u64 = u64::max_value();
+
 impl BootInfoFrameAllocator {
-    // Usable regions in the memory map should actually be unused
-    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+    // Usable regions in the memory map should actually be unused, and complete physical memory
+    // must be mapped at the offset
+    pub unsafe fn new(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
         BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            physical_memory_offset,
+            region: 0,
+            next: memory_map.first().map_or(0, |r| r.range.start_addr()),
+            free_stack: None,
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
+    /// Takes `size` bytes of never-allocated memory, aligned to `size`
+    ///
+    /// Frames skipped over to reach the alignment, or a region with room, go on the free stack so
+    /// they aren't lost. If no region has room nothing changes, so smaller requests still work.
+    fn next_unused_range(&mut self, size: u64) -> Option<PhysAddr> {
+        let memory_map = self.memory_map;
+        let (first, next) = (self.region, self.next);
+        // Where never-allocated memory starts in a usable region at or after the current one
+        let unused_start = |i: usize, start: u64| if i == first { next } else { start };
+
+        let (index, start) = memory_map
             .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .enumerate()
+            .skip(first)
+            .filter(|(_, region)| region.region_type == MemoryRegionType::Usable)
+            .find_map(|(i, region)| {
+                let from = unused_start(i, region.range.start_addr());
+                let start = from.checked_add(size - 1)? & !(size - 1);
+                let end = start.checked_add(size)?;
+                if end <= region.range.end_addr() {
+                    Some((i, start))
+                } else {
+                    None
+                }
+            })?;
+
+        let skipped = memory_map.iter().enumerate().take(index + 1).skip(first);
+        for (i, region) in skipped {
+            if region.region_type == MemoryRegionType::Usable {
+                let from = unused_start(i, region.range.start_addr());
+                let to = if i == index {
+                    start
+                } else {
+                    region.range.end_addr()
+                };
+                self.free_range(from, to);
+            }
+        }
+        self.region = index;
+        self.next = start + size;
+        Some(PhysAddr::new(start))
+    }
+
+    /// Allocates a 4KiB frame and fills it with zeroes through the physical memory mapping
+    pub fn allocate_zeroed_frame(&mut self) -> Option<UnusedPhysFrame> {
+        let frame: UnusedPhysFrame = self.allocate_frame()?;
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize) };
+        Some(frame)
+    }
+
+    fn allocate_huge_frame<S: PageSize>(&mut self) -> Option<UnusedPhysFrame<S>> {
+        let addr = self.next_unused_range(S::SIZE)?;
+        Some(unsafe { UnusedPhysFrame::new(PhysFrame::containing_address(addr)) })
+    }
+
+    // The free stack link of a freed frame is stored at the start of the frame
+    fn link(&self, frame: PhysFrame) -> *mut u64 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    fn push_free(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_stack
+            .map_or(FREE_STACK_END, |f| f.start_address().as_u64());
+        unsafe { self.link(frame).write(next) };
+        self.free_stack = Some(frame);
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_stack?;
+        let next = unsafe { self.link(frame).read() };
+        self.free_stack = if next == FREE_STACK_END {
+            None
+        } else {
+            Some(PhysFrame::containing_address(PhysAddr::new(next)))
+        };
+        Some(frame)
+    }
+
+    fn free_range(&mut self, start: u64, end: u64) {
+        for addr in (start..end).step_by(Size4KiB::SIZE as usize) {
+            self.push_free(PhysFrame::containing_address(PhysAddr::new(addr)));
+        }
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let frame = unsafe {
-            self.usable_frames()
-                .nth(self.next)
-                .map(|f| UnusedPhysFrame::new(f))
+        let frame = match self.pop_free() {
+            Some(frame) => frame,
+            None => PhysFrame::containing_address(self.next_unused_range(Size4KiB::SIZE)?),
+        };
+
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        self.allocate_huge_frame()
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size1GiB>> {
+        // Don't use up a gigabyte that can never be mapped
+        if !has_1gib_pages() {
+            return None;
+        }
+        self.allocate_huge_frame()
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<S>) {
+        let start = frame.start_address().as_u64();
+        self.free_range(start, start + S::SIZE);
+    }
+}
+
+/// Returns whether the CPU supports 1 GiB pages (CPUID.80000001h:EDX.Page1GB)
+///
+/// QEMU's default CPU doesn't, and mapping one anyway faults with a reserved bit error.
+pub fn has_1gib_pages() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+// Fails before touching the page tables if S is a page size the CPU can't map
+fn check_page_size<S: PageSize>() -> Result<(), MapError> {
+    if S::SIZE == Size1GiB::SIZE && !has_1gib_pages() {
+        Err(MapError::PageSizeNotSupported)
+    } else {
+        Ok(())
+    }
+}
+
+/// Why mapping pages failed
+///
+/// Unlike `MapToError`, it never holds a frame, so whoever allocated the frame has already freed
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// No frame was left for the page or for a page table
+    FrameAllocationFailed,
+    /// A huge page is already mapped where a page table is needed
+    ParentEntryHugePage,
+    PageAlreadyMapped,
+    /// The CPU can't map pages of this size
+    PageSizeNotSupported,
+}
+
+// Drops the frame of `PageAlreadyMapped`, so only for frames the caller doesn't own
+impl<S: PageSize> From<MapToError<S>> for MapError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => MapError::PageAlreadyMapped,
+        }
+    }
+}
+
+/// Maps every page in the range to a newly allocated frame
+///
+/// Works for 4KiB pages as well as 2MiB and 1GiB huge pages, as long as the frame allocator can
+/// hand out frames of that size. If mapping fails partway, the pages mapped so far are unmapped
+/// and their frames freed again.
+pub fn map_range<S, A>(
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut A,
+) -> Result<(), MapError>
+where
+    S: PageSize,
+    A: FrameAllocator<S> + FrameDeallocator<S> + FrameAllocator<Size4KiB>,
+{
+    check_page_size::<S>()?;
+    let start = pages.start;
+    for page in pages {
+        let result = match FrameAllocator::<S>::allocate_frame(frame_allocator) {
+            Some(frame) => {
+                let phys_frame = *frame;
+                match mapper.map_to(page, frame, flags, frame_allocator) {
+                    Ok(flush) => Ok(flush),
+                    // Only PageAlreadyMapped hands the frame back, the other errors drop it
+                    Err(MapToError::PageAlreadyMapped(frame)) => {
+                        FrameDeallocator::<S>::deallocate_frame(frame_allocator, frame);
+                        Err(MapError::PageAlreadyMapped)
+                    }
+                    Err(err) => {
+                        let frame = unsafe { UnusedPhysFrame::new(phys_frame) };
+                        FrameDeallocator::<S>::deallocate_frame(frame_allocator, frame);
+                        Err(MapError::from(err))
+                    }
+                }
+            }
+            None => Err(MapError::FrameAllocationFailed),
         };
-        self.next += 1;
-        frame
+
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unmap_and_free_range(Page::range(start, page), mapper, frame_allocator)
+                    .expect("rolling back partial mapping failed");
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps the pages to consecutive physical frames starting at `start_frame`, e.g. for MMIO
+///
+/// If mapping fails partway, the pages mapped so far are unmapped again. Unsafe since the frames
+/// must not already be in use, unless aliasing them is intended.
+pub unsafe fn map_range_to<S: PageSize>(
+    pages: PageRange<S>,
+    start_frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapError> {
+    check_page_size::<S>()?;
+    let start = pages.start;
+    for (i, page) in pages.enumerate() {
+        let frame = UnusedPhysFrame::new(start_frame + i as u64);
+
+        match mapper.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unmap_range(Page::range(start, page), mapper)
+                    .expect("rolling back partial mapping failed");
+                return Err(err.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmaps every page in the range and flushes it from the TLB, leaving the frames allocated
+///
+/// Stops at the first page that can't be unmapped.
+pub fn unmap_range<S: PageSize>(
+    pages: PageRange<S>,
+    mapper: &mut impl Mapper<S>,
+) -> Result<(), UnmapError> {
+    for page in pages {
+        let (_, flush) = mapper.unmap(page)?;
+        flush.flush();
+    }
+
+    Ok(())
+}
+
+/// Unmaps every page in the range, flushes it from the TLB and frees its frame
+///
+/// The frames must not be mapped anywhere else. Stops at the first page that can't be unmapped.
+pub fn unmap_and_free_range<S: PageSize>(
+    pages: PageRange<S>,
+    mapper: &mut impl Mapper<S>,
+    frame_deallocator: &mut impl FrameDeallocator<S>,
+) -> Result<(), UnmapError> {
+    for page in pages {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        frame_deallocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+    }
+
+    Ok(())
+}
+
+/// Replaces the flags of every mapped page in the range and flushes it from the TLB
+///
+/// Stops at the first page that can't be updated. Huge pages keep their `HUGE_PAGE` flag.
+pub fn protect_range<S: PageSize>(
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<S>,
+) -> Result<(), FlagUpdateError> {
+    let flags = if S::SIZE == Size4KiB::SIZE {
+        flags
+    } else {
+        flags | PageTableFlags::HUGE_PAGE
+    };
+
+    for page in pages {
+        mapper.update_flags(page, flags)?.flush();
+    }
+
+    Ok(())
+}
+
+/// Reserves and maps a kernel stack of at least `size` bytes, returning its top
+///
+/// The lowest page of the reserved region is left unmapped, so overflowing the stack faults
+/// instead of running into whatever lies below.
+pub fn allocate_stack<A>(
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<VirtAddr, MapError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = ADDRESS_SPACE
+        .lock()
+        .reserve(size + Size4KiB::SIZE, RegionKind::KernelStack, flags)
+        .expect("no virtual memory left for a kernel stack");
+
+    let pages = region.pages();
+    let stack_pages = Page::range(pages.start + 1, pages.end);
+    if let Err(err) = map_range(stack_pages, flags, mapper, frame_allocator) {
+        ADDRESS_SPACE.lock().release(region.start).unwrap();
+        return Err(err);
+    }
+
+    Ok(region.end())
+}
+
+/// Maps `size` bytes of device memory starting at `phys` into a new uncached MMIO region
+///
+/// Returns the virtual address of `phys`. Uses `MAPPER` and `FRAME_ALLOCATOR`, so it must not be
+/// called while holding either.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let region = ADDRESS_SPACE
+        .lock()
+        .reserve(offset + size, RegionKind::Mmio, flags)
+        .expect("no virtual memory left for MMIO");
+
+    let result = {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mapper = mapper.as_mut().expect("memory isn't initialized");
+        let frame_allocator = frame_allocator.as_mut().expect("memory isn't initialized");
+        let frame = PhysFrame::containing_address(phys);
+        unsafe { map_range_to(region.pages(), frame, flags, mapper, frame_allocator) }
+    };
+    if let Err(err) = result {
+        ADDRESS_SPACE.lock().release(region.start).unwrap();
+        return Err(err);
+    }
+
+    Ok(region.start + offset)
+}
+
+/// Unmaps the MMIO region `map_mmio` returned `addr` in, and releases its virtual memory
+///
+/// Uses `MAPPER`, so it must not be called while holding it.
+pub fn unmap_mmio(addr: VirtAddr) {
+    let region = ADDRESS_SPACE
+        .lock()
+        .find(addr)
+        .filter(|region| region.kind == RegionKind::Mmio)
+        .expect("address isn't in an MMIO region");
+
+    {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("memory isn't initialized");
+        unmap_range(region.pages(), mapper).expect("MMIO region isn't mapped");
+    }
+    ADDRESS_SPACE.lock().release(region.start).unwrap();
+}
+
+/// Resolves a page fault by mapping a zeroed frame, if the address is in a demand backed region
+///
+/// Returns false if the fault has to be treated as fatal: it's a protection violation, the address
+/// isn't in a demand backed region, or the memory globals are held by the code that faulted.
+pub fn handle_page_fault(addr: VirtAddr, error: PageFaultErrorCode) -> bool {
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    // Only try_lock, since the faulting code may hold any of these locks
+    let region = match ADDRESS_SPACE.try_lock().and_then(|space| space.find(addr)) {
+        Some(region) if region.backing == Backing::Demand => region,
+        _ => return false,
+    };
+    let mut mapper = MAPPER.try_lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock();
+    let (mapper, frame_allocator) = match (
+        mapper.as_mut().and_then(|m| m.as_mut()),
+        frame_allocator.as_mut().and_then(|f| f.as_mut()),
+    ) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let frame = match frame_allocator.allocate_zeroed_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match mapper.map_to(page, frame, region.flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => false,
     }
 }
 
@@ -68,7 +497,125 @@ pub fn create_mapping(
     let frame = PhysFrame::containing_address(addr);
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
-    let result =
-        unsafe { mapper.map_to(page, UnusedPhysFrame::new(frame), flags, frame_allocator) };
-    result.expect("map_to failed").flush();
+    let pages = Page::range(page, page + 1);
+    let result = unsafe { map_range_to(pages, frame, flags, mapper, frame_allocator) };
+    result.expect("map_to failed");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::structures::paging::MapperAllSizes;
+
+    test!(reuses_freed_frame {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+
+        let first: UnusedPhysFrame = frame_allocator.allocate_frame().unwrap();
+        let second: UnusedPhysFrame = frame_allocator.allocate_frame().unwrap();
+        let (first_addr, second_addr) = (first.start_address(), second.start_address());
+        assert_ne!(first_addr, second_addr);
+
+        frame_allocator.deallocate_frame(first);
+        frame_allocator.deallocate_frame(second);
+        // Freed frames come back in LIFO order
+        let frame: UnusedPhysFrame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address(), second_addr);
+        let frame: UnusedPhysFrame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address(), first_addr);
+    });
+
+    test!(huge_frames_are_aligned {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+
+        let frame: UnusedPhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64() % Size2MiB::SIZE, 0);
+        frame_allocator.deallocate_frame(frame);
+    });
+
+    test!(failed_huge_frames_use_nothing_up {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+
+        // QEMU doesn't have a free gigabyte of memory
+        let before = (
+            frame_allocator.region,
+            frame_allocator.next,
+            frame_allocator.free_stack,
+        );
+        assert!(frame_allocator.next_unused_range(Size1GiB::SIZE).is_none());
+        let after = (
+            frame_allocator.region,
+            frame_allocator.next,
+            frame_allocator.free_stack,
+        );
+        assert_eq!(before, after);
+
+        let frame: UnusedPhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+        frame_allocator.deallocate_frame(frame);
+    });
+
+    test!(gib_pages_need_cpu_support {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let region = ADDRESS_SPACE
+            .lock()
+            .reserve_aligned(Size1GiB::SIZE, Size1GiB::SIZE, vma::RegionKind::Task, flags)
+            .unwrap();
+        let pages = Page::<Size1GiB>::range(
+            Page::containing_address(region.start),
+            Page::containing_address(region.start) + 1,
+        );
+
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let frame: Option<UnusedPhysFrame<Size1GiB>> = frame_allocator.allocate_frame();
+        if !has_1gib_pages() {
+            assert!(frame.is_none());
+        }
+        if let Some(frame) = frame {
+            frame_allocator.deallocate_frame(frame);
+        }
+
+        // Without CPU support this is refused up front, and QEMU doesn't have a free gigabyte
+        // of memory anyway
+        let result = map_range(pages, flags, mapper, frame_allocator);
+        if has_1gib_pages() {
+            assert!(result.is_err());
+        } else {
+            assert_eq!(result, Err(MapError::PageSizeNotSupported));
+        }
+        assert_eq!(mapper.translate_addr(region.start), None);
+        ADDRESS_SPACE.lock().release(region.start).unwrap();
+    });
+
+    test!(demand_paging {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let region = ADDRESS_SPACE
+            .lock()
+            .reserve_on_demand(4 * Size4KiB::SIZE, vma::RegionKind::Task, flags)
+            .unwrap();
+        let ptr: *mut u64 = region.start.as_mut_ptr();
+
+        // Touching the region faults its pages in, zeroed
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.add(1024).write_volatile(42);
+            assert_eq!(ptr.add(1024).read_volatile(), 42);
+        }
+
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        // Only the touched pages got mapped
+        for (i, page) in region.pages().enumerate() {
+            let pages = Page::range(page, page + 1);
+            let result = unmap_and_free_range(pages, mapper, frame_allocator);
+            assert_eq!(result.is_ok(), i == 0 || i == 2);
+        }
+        ADDRESS_SPACE.lock().release(region.start).unwrap();
+    });
 }
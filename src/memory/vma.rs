@@ -0,0 +1,345 @@
+use bootloader::bootinfo::MemoryMap;
+use spin::Mutex;
+use x86_64::structures::paging::{page::PageRange, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// Kernel virtual memory that regions are handed out from
+pub const KERNEL_SPACE_START: u64 = 0x_4000_0000_0000;
+pub const KERNEL_SPACE_END: u64 = 0x_7000_0000_0000;
+
+const MAX_REGIONS: usize = 64;
+
+/// What a region of kernel virtual memory is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    KernelStack,
+    Mmio,
+    Task,
+    /// The bootloader's mapping of all physical memory
+    PhysicalMemory,
+}
+
+/// How the pages of a region get mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// The owner of the region maps its pages
+    Manual,
+    /// Pages are mapped to zeroed frames by the page fault handler when first touched
+    Demand,
+}
+
+/// A reserved range of virtual memory
+///
+/// Reserving a region doesn't map anything, it only keeps other users out of the range. `flags`
+/// are the page table flags the region's pages are meant to be mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.as_u64() < end && start < self.end().as_u64()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The range collides with an existing region
+    Overlap(RegionKind),
+    /// The range isn't inside the kernel range
+    OutOfRange,
+    /// The kernel range has no gap big enough
+    OutOfSpace,
+    /// The region table is full
+    TooManyRegions,
+    /// No region starts at the given address
+    NotFound,
+    Unaligned,
+}
+
+/// Keeps track of which kernel virtual memory is used for what
+///
+/// Regions placed automatically are always followed by an unmapped guard page, so running off the
+/// end of one faults instead of corrupting the next.
+pub struct AddressSpace {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    /// Reserves a region at a fixed address
+    ///
+    /// Only `PhysicalMemory` regions may lie outside the kernel range, since the bootloader picks
+    /// where that mapping goes.
+    pub fn reserve_at(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmaError> {
+        if start.as_u64() % PAGE_SIZE != 0 {
+            return Err(VmaError::Unaligned);
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let end = start
+            .as_u64()
+            .checked_add(size)
+            .ok_or(VmaError::OutOfRange)?;
+        if kind != RegionKind::PhysicalMemory
+            && (start.as_u64() < KERNEL_SPACE_START || end > KERNEL_SPACE_END)
+        {
+            return Err(VmaError::OutOfRange);
+        }
+        if let Some(region) = self.overlapping(start.as_u64(), end) {
+            return Err(VmaError::Overlap(region.kind));
+        }
+
+        self.insert(Region {
+            start,
+            size,
+            kind,
+            flags,
+            backing: Backing::Manual,
+        })
+    }
+
+    /// Reserves a page aligned region anywhere in the kernel range
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmaError> {
+        self.reserve_aligned(size, PAGE_SIZE, kind, flags)
+    }
+
+    /// Reserves a page aligned region whose pages are only backed by memory once touched
+    ///
+    /// Nothing needs to be mapped up front, so big regions don't commit physical memory until
+    /// they're used.
+    pub fn reserve_on_demand(
+        &mut self,
+        size: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmaError> {
+        self.place(size, PAGE_SIZE, kind, flags, Backing::Demand)
+    }
+
+    /// Reserves a region aligned to `align` anywhere in the kernel range, e.g. for huge pages
+    ///
+    /// `align` must be a power of 2 and at least the page size.
+    pub fn reserve_aligned(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmaError> {
+        self.place(size, align, kind, flags, Backing::Manual)
+    }
+
+    fn place(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<Region, VmaError> {
+        if !align.is_power_of_two() || align < PAGE_SIZE {
+            return Err(VmaError::Unaligned);
+        }
+        let size = align_up(size, PAGE_SIZE);
+
+        // First fit, leaving a guard page after every region in the way
+        let mut start = align_up(KERNEL_SPACE_START, align);
+        loop {
+            let end = start.checked_add(size).ok_or(VmaError::OutOfSpace)?;
+            if end > KERNEL_SPACE_END {
+                return Err(VmaError::OutOfSpace);
+            }
+            match self.overlapping(start, end + PAGE_SIZE) {
+                Some(region) => start = align_up(region.end().as_u64() + PAGE_SIZE, align),
+                None => break,
+            }
+        }
+
+        self.insert(Region {
+            start: VirtAddr::new(start),
+            size,
+            kind,
+            flags,
+            backing,
+        })
+    }
+
+    /// Removes the region starting at `start`, without unmapping anything
+    pub fn release(&mut self, start: VirtAddr) -> Result<Region, VmaError> {
+        self.regions
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |r| r.start == start))
+            .and_then(|slot| slot.take())
+            .ok_or(VmaError::NotFound)
+    }
+
+    /// Returns the region containing the address
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions().find(|r| r.contains(addr))
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter().filter_map(|&r| r)
+    }
+
+    fn overlapping(&self, start: u64, end: u64) -> Option<Region> {
+        self.regions().find(|r| r.overlaps(start, end))
+    }
+
+    fn insert(&mut self, region: Region) -> Result<Region, VmaError> {
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(region)
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+pub static ADDRESS_SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new());
+
+/// Reserves the bootloader's physical memory mapping so nothing else gets placed on top of it
+pub fn init(physical_memory_offset: VirtAddr, memory_map: &MemoryMap) {
+    let physical_memory_size = memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    ADDRESS_SPACE
+        .lock()
+        .reserve_at(
+            physical_memory_offset,
+            physical_memory_size,
+            RegionKind::PhysicalMemory,
+            flags,
+        )
+        .expect("physical memory mapping collides with another region");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flags() -> PageTableFlags {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    }
+
+    test!(reserve_without_overlap {
+        let mut space = AddressSpace::new();
+        let a = space.reserve(3 * PAGE_SIZE, RegionKind::KernelStack, flags()).unwrap();
+        let b = space.reserve(100, RegionKind::Task, flags()).unwrap();
+
+        assert_eq!(a.start.as_u64(), KERNEL_SPACE_START);
+        assert_eq!(b.size, PAGE_SIZE);
+        // Separated by a guard page
+        assert_eq!(b.start, a.end() + PAGE_SIZE);
+        assert_eq!(space.find(b.start + 10u64), Some(b));
+        assert_eq!(space.find(a.end()), None);
+    });
+
+    test!(reserve_at_refuses_collisions {
+        let mut space = AddressSpace::new();
+        let start = VirtAddr::new(KERNEL_SPACE_START + 16 * PAGE_SIZE);
+        space.reserve_at(start, 4 * PAGE_SIZE, RegionKind::Heap, flags()).unwrap();
+
+        let inside = start + 2 * PAGE_SIZE;
+        assert_eq!(
+            space.reserve_at(inside, PAGE_SIZE, RegionKind::Mmio, flags()),
+            Err(VmaError::Overlap(RegionKind::Heap))
+        );
+        assert_eq!(
+            space.reserve_at(VirtAddr::new(0x1000), PAGE_SIZE, RegionKind::Mmio, flags()),
+            Err(VmaError::OutOfRange)
+        );
+        assert_eq!(
+            space.reserve_at(start + 1u64, PAGE_SIZE, RegionKind::Mmio, flags()),
+            Err(VmaError::Unaligned)
+        );
+    });
+
+    test!(physical_memory_window_is_avoided {
+        let mut space = AddressSpace::new();
+        let window = VirtAddr::new(KERNEL_SPACE_START);
+        space
+            .reserve_at(window, 64 * PAGE_SIZE, RegionKind::PhysicalMemory, flags())
+            .unwrap();
+
+        assert_eq!(
+            space.reserve_at(window, PAGE_SIZE, RegionKind::Heap, flags()),
+            Err(VmaError::Overlap(RegionKind::PhysicalMemory))
+        );
+        let region = space.reserve(PAGE_SIZE, RegionKind::Task, flags()).unwrap();
+        assert!(region.start >= window + 64 * PAGE_SIZE);
+    });
+
+    test!(release_frees_range {
+        let mut space = AddressSpace::new();
+        let region = space.reserve(PAGE_SIZE, RegionKind::Mmio, flags()).unwrap();
+        assert_eq!(space.release(region.start), Ok(region));
+        assert_eq!(space.release(region.start), Err(VmaError::NotFound));
+        assert_eq!(space.reserve(PAGE_SIZE, RegionKind::Mmio, flags()), Ok(region));
+    });
+
+    test!(reserve_aligned_for_huge_pages {
+        let mut space = AddressSpace::new();
+        space.reserve(PAGE_SIZE, RegionKind::Task, flags()).unwrap();
+        let align = 2 * 1024 * 1024;
+        let region = space.reserve_aligned(align, align, RegionKind::Heap, flags()).unwrap();
+        assert_eq!(region.start.as_u64() % align, 0);
+    });
+
+    test!(reserve_on_demand {
+        let mut space = AddressSpace::new();
+        let eager = space.reserve(PAGE_SIZE, RegionKind::Task, flags()).unwrap();
+        let lazy = space.reserve_on_demand(PAGE_SIZE, RegionKind::Task, flags()).unwrap();
+        assert_eq!(eager.backing, Backing::Manual);
+        assert_eq!(lazy.backing, Backing::Demand);
+        assert_eq!(space.find(lazy.start).map(|r| r.backing), Some(Backing::Demand));
+    });
+}
@@ -0,0 +1,114 @@
+pub mod executor;
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// A spawned future, run by an `executor::Executor` until it completes
+struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Identifies a task, so its waker can queue it. Never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl TaskId {
+    fn new() -> Self {
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// An asynchronous sequence of values, the async version of `Iterator`
+pub trait Stream {
+    type Item;
+
+    /// Returns `Ready(None)` once the stream has ended
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+pub trait StreamExt: Stream {
+    /// Waits for the next value of the stream
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// Future returned by `StreamExt::next`
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Holds the waker of the task waiting on an interrupt
+///
+/// Interrupt handlers only wake it by reference, so they never drop the last reference to a waker
+/// and free memory. Only one task can wait on a slot at a time.
+pub struct WakerSlot {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> Self {
+        Self {
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Makes `wake` wake `waker`, replacing the previous one
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            match &*slot {
+                Some(old) if old.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    pub fn clear(&self) {
+        let old = interrupts::without_interrupts(|| self.waker.lock().take());
+        drop(old);
+    }
+
+    /// Wakes the registered task, if any. Safe to call from interrupt handlers.
+    pub fn wake(&self) {
+        interrupts::without_interrupts(|| {
+            if let Some(waker) = &*self.waker.lock() {
+                waker.wake_by_ref();
+            }
+        });
+    }
+}
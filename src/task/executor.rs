@@ -0,0 +1,338 @@
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
+
+/// Runs tasks when they're woken, and halts the CPU while none are
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Entry>,
+    wake_queue: Arc<WakeQueue>,
+}
+
+struct Entry {
+    task: Task,
+    state: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            wake_queue: Arc::new(WakeQueue::new()),
+        }
+    }
+
+    /// Adds a task, which first runs the next time the executor runs
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        let task = Task::new(future);
+        let id = task.id;
+        let state = Arc::new(TaskWaker {
+            id,
+            wake_queue: self.wake_queue.clone(),
+            queued: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        });
+        let waker = state.clone().into_waker();
+        self.tasks.insert(id, Entry { task, state, waker });
+        self.tasks[&id].waker.wake_by_ref();
+    }
+
+    /// Runs woken tasks until none are left to run
+    pub fn run_ready_tasks(&mut self) {
+        loop {
+            while let Some(id) = self.wake_queue.pop() {
+                self.run_task(id);
+            }
+            if !self.wake_queue.take_overflow() {
+                break;
+            }
+
+            // Some wakeups didn't fit in the queue, but their tasks are still marked as queued
+            let woken: Vec<TaskId> = self
+                .tasks
+                .iter()
+                .filter(|(_, entry)| entry.state.queued.load(Ordering::Acquire))
+                .map(|(&id, _)| id)
+                .collect();
+            for id in woken {
+                self.run_task(id);
+            }
+        }
+    }
+
+    fn run_task(&mut self, id: TaskId) {
+        // Tasks that finished can still be queued
+        let entry = match self.tasks.get_mut(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        // Wakeups from now on must run the task again
+        entry.state.queued.store(false, Ordering::Release);
+        let mut cx = Context::from_waker(&entry.waker);
+        if let Poll::Ready(()) = entry.task.poll(&mut cx) {
+            entry.state.done.store(true, Ordering::Release);
+            self.tasks.remove(&id);
+        }
+    }
+
+    /// Returns how many wakeups didn't fit in the wake queue
+    ///
+    /// Their tasks still run, but only after every queued task did.
+    pub fn dropped_wakeups(&self) -> u64 {
+        self.wake_queue.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of tasks that haven't finished
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // An interrupt could wake a task between the check and the hlt, so check with interrupts
+        // disabled and reenable them atomically with the hlt
+        interrupts::disable();
+        if self.wake_queue.is_empty() {
+            enable_interrupts_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+const WAKE_QUEUE_SIZE: usize = 256;
+
+/// IDs of the tasks to run next, in the order they were woken
+///
+/// Pushed to by interrupt handlers, so it has a fixed size and is only locked with interrupts
+/// disabled. Wakeups that don't fit are dropped and counted, and the executor goes looking for
+/// their tasks.
+struct WakeQueue {
+    ring: Mutex<Ring>,
+    overflowed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+struct Ring {
+    ids: [TaskId; WAKE_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl WakeQueue {
+    fn new() -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                ids: [TaskId(0); WAKE_QUEUE_SIZE],
+                head: 0,
+                len: 0,
+            }),
+            overflowed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, id: TaskId) {
+        let pushed = interrupts::without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == WAKE_QUEUE_SIZE {
+                return false;
+            }
+            let tail = (ring.head + ring.len) % WAKE_QUEUE_SIZE;
+            ring.ids[tail] = id;
+            ring.len += 1;
+            true
+        });
+
+        if !pushed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            if ring.len == 0 {
+                return None;
+            }
+            let id = ring.ids[ring.head];
+            ring.head = (ring.head + 1) % WAKE_QUEUE_SIZE;
+            ring.len -= 1;
+            Some(id)
+        })
+    }
+
+    // Returns whether wakeups were dropped since the last call
+    fn take_overflow(&self) -> bool {
+        self.overflowed.swap(false, Ordering::AcqRel)
+    }
+
+    // Also false while dropped wakeups haven't been dealt with
+    fn is_empty(&self) -> bool {
+        let ring_empty = interrupts::without_interrupts(|| self.ring.lock().len == 0);
+        ring_empty && !self.overflowed.load(Ordering::Acquire)
+    }
+}
+
+/// Shared by all the wakers of a task
+struct TaskWaker {
+    id: TaskId,
+    wake_queue: Arc<WakeQueue>,
+    // Set while the task is in the wake queue, so it's never queued twice
+    queued: AtomicBool,
+    done: AtomicBool,
+}
+
+impl TaskWaker {
+    fn wake(&self) {
+        if self.done.load(Ordering::Acquire) || self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.wake_queue.push(self.id);
+    }
+
+    fn into_waker(self: Arc<Self>) -> Waker {
+        unsafe { Waker::from_raw(raw_waker(Arc::into_raw(self))) }
+    }
+}
+
+// Each RawWaker owns one reference to a TaskWaker
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+fn raw_waker(ptr: *const TaskWaker) -> RawWaker {
+    RawWaker::new(ptr as *const (), &VTABLE)
+}
+
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+    let state = ManuallyDrop::new(Arc::from_raw(ptr as *const TaskWaker));
+    raw_waker(Arc::into_raw(Arc::clone(&state)))
+}
+
+unsafe fn wake(ptr: *const ()) {
+    let state = Arc::from_raw(ptr as *const TaskWaker);
+    state.wake();
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+    let state = ManuallyDrop::new(Arc::from_raw(ptr as *const TaskWaker));
+    state.wake();
+}
+
+unsafe fn drop_waker(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const TaskWaker));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use core::pin::Pin;
+
+    // Returns Pending once, after waking itself or not
+    struct YieldOnce {
+        yielded: bool,
+        wake: bool,
+    }
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.yielded {
+                return Poll::Ready(());
+            }
+            self.yielded = true;
+            if self.wake {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+
+    test!(runs_spawned_tasks {
+        let runs = Rc::new(Cell::new(0));
+        let mut executor = Executor::new();
+        for _ in 0..3 {
+            let runs = runs.clone();
+            executor.spawn(async move { runs.set(runs.get() + 1) });
+        }
+        assert_eq!(runs.get(), 0);
+
+        executor.run_ready_tasks();
+        assert_eq!(runs.get(), 3);
+        assert_eq!(executor.task_count(), 0);
+    });
+
+    test!(runs_woken_tasks_again {
+        let order = Rc::new(Cell::new(0u32));
+        let mut executor = Executor::new();
+
+        let first = order.clone();
+        executor.spawn(async move {
+            YieldOnce { yielded: false, wake: true }.await;
+            // The second task got to run while this one was yielding
+            assert_eq!(first.get(), 1);
+            first.set(2);
+        });
+        let second = order.clone();
+        executor.spawn(async move { second.set(1) });
+
+        executor.run_ready_tasks();
+        assert_eq!(order.get(), 2);
+        assert_eq!(executor.task_count(), 0);
+    });
+
+    test!(pending_tasks_wait_for_wakeups {
+        let mut executor = Executor::new();
+        executor.spawn(YieldOnce { yielded: false, wake: false });
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 1);
+
+        // Running again without a wakeup does nothing
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 1);
+
+        let id = *executor.tasks.keys().next().unwrap();
+        let waker = executor.tasks[&id].waker.clone();
+        waker.wake_by_ref();
+        // Waking twice only queues the task once
+        waker.wake_by_ref();
+        executor.run_ready_tasks();
+        assert_eq!(executor.task_count(), 0);
+        assert!(executor.wake_queue.is_empty());
+
+        // Wakers of finished tasks don't queue anything
+        waker.wake();
+        assert!(executor.wake_queue.is_empty());
+    });
+
+    test!(dropped_wakeups_still_run_tasks {
+        let runs = Rc::new(Cell::new(0));
+        let mut executor = Executor::new();
+        for _ in 0..WAKE_QUEUE_SIZE + 10 {
+            let runs = runs.clone();
+            executor.spawn(async move { runs.set(runs.get() + 1) });
+        }
+        assert_eq!(executor.dropped_wakeups(), 10);
+        assert!(!executor.wake_queue.is_empty());
+
+        executor.run_ready_tasks();
+        assert_eq!(runs.get(), WAKE_QUEUE_SIZE + 10);
+        assert_eq!(executor.task_count(), 0);
+        assert!(executor.wake_queue.is_empty());
+    });
+}
@@ -23,6 +23,38 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     crate::hlt_loop()
 }
 
+/// Checks whether a panic's message contains `expected`, for tests that panic on purpose
+///
+/// Only the first 512 bytes of the message are searched.
+pub fn panic_message_contains(info: &PanicInfo, expected: &str) -> bool {
+    use core::fmt::Write;
+
+    struct Buffer {
+        bytes: [u8; 512],
+        len: usize,
+    }
+
+    impl core::fmt::Write for Buffer {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let n = s.len().min(self.bytes.len() - self.len);
+            self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            Ok(())
+        }
+    }
+
+    let mut buf = Buffer {
+        bytes: [0; 512],
+        len: 0,
+    };
+    let _ = write!(buf, "{}", info);
+
+    expected.is_empty()
+        || buf.bytes[..buf.len]
+            .windows(expected.len())
+            .any(|window| window == expected.as_bytes())
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
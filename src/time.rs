@@ -0,0 +1,122 @@
+pub mod rtc;
+pub mod tsc;
+
+use crate::event;
+use crate::interrupts::{irq, InterruptIndex};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT's input clock
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+pub const DEFAULT_TICK_RATE: u32 = 100;
+
+// Channel 0, lobyte/hibyte access, mode 2 (rate generator)
+const PIT_CHANNEL_0_RATE: u8 = 0b0011_0100;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL_0: u16 = 0x40;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// PIT input cycles elapsed over all ticks, so uptime stays exact across rate changes
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+// The BIOS leaves the PIT at its slowest rate
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+
+/// Programs the PIT to tick at about `hz` and starts counting ticks
+pub fn init(hz: u32) {
+    set_tick_rate(hz);
+    irq::register(InterruptIndex::Timer.irq(), tick);
+}
+
+/// Reprograms the PIT to tick at about `hz`, returning the rate it actually ticks at
+///
+/// The PIT can't go slower than about 18.2 Hz, or faster than half its base frequency.
+pub fn set_tick_rate(hz: u32) -> u32 {
+    let divisor = divisor(hz);
+    interrupts::without_interrupts(|| {
+        DIVISOR.store(divisor, Ordering::Relaxed);
+        unsafe {
+            Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL_0_RATE);
+            // A divisor of 65536 is written as 0
+            let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+            channel.write(divisor as u8);
+            channel.write((divisor >> 8) as u8);
+        }
+    });
+    tick_rate()
+}
+
+// The rate generator mode doesn't allow a divisor of 1
+fn divisor(hz: u32) -> u32 {
+    let hz = u64::from(hz.max(1));
+    ((PIT_FREQUENCY + hz / 2) / hz).max(2).min(65536) as u32
+}
+
+/// Returns how many times per second the PIT ticks, rounded
+pub fn tick_rate() -> u32 {
+    let divisor = u64::from(DIVISOR.load(Ordering::Relaxed));
+    ((PIT_FREQUENCY + divisor / 2) / divisor) as u32
+}
+
+/// Returns the exact time between two ticks
+pub fn tick_period() -> Duration {
+    cycles_to_duration(u64::from(DIVISOR.load(Ordering::Relaxed)))
+}
+
+// Should only be called by interrupt
+fn tick() {
+    CYCLES.fetch_add(
+        u64::from(DIVISOR.load(Ordering::Relaxed)),
+        Ordering::Relaxed,
+    );
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    event::timer::update();
+}
+
+/// Returns how many timer ticks happened since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since `init`, with the timer's resolution
+///
+/// Never goes backwards, even when the tick rate changes.
+pub fn uptime() -> Duration {
+    cycles_to_duration(CYCLES.load(Ordering::Relaxed))
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(PIT_FREQUENCY);
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    test!(divisor_is_clamped {
+        assert_eq!(divisor(1000), 1193);
+        assert_eq!(divisor(1), 65536);
+        assert_eq!(divisor(0), 65536);
+        assert_eq!(divisor(u32::max_value()), 2);
+        assert_eq!(divisor(PIT_FREQUENCY as u32), 2);
+    });
+
+    test!(uptime_follows_ticks {
+        let start = uptime();
+        let start_ticks = ticks();
+        while ticks() < start_ticks + 10 {
+            x86_64::instructions::hlt();
+        }
+
+        // Ticks may land right before or after the reads
+        let elapsed = uptime() - start;
+        let period = tick_period();
+        assert!(elapsed >= period * 9 && elapsed <= period * 11);
+        assert_eq!(cycles_to_duration(PIT_FREQUENCY), Duration::from_secs(1));
+    });
+}
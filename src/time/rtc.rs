@@ -0,0 +1,222 @@
+use crate::event;
+use crate::interrupts::irq;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const RTC_IRQ: u8 = 8;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const STATUS_D: u8 = 0x0d;
+
+// Set in the register select port to mask NMIs, so one can't come in between selecting a
+// register and accessing it
+const NMI_DISABLE: u8 = 1 << 7;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Interrupt enable bits in status B, and the matching flags in status C
+pub(crate) const PERIODIC_INTERRUPT: u8 = 1 << 6;
+pub(crate) const UPDATE_ENDED_INTERRUPT: u8 = 1 << 4;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_24_MODE: u8 = 1 << 1;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Calendar date and time as kept by the RTC, usually in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Interrupts the RTC can raise on IRQ 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcInterrupt {
+    /// Fires at `32768 >> (rate - 1)` Hz, for `rate` from 3 (8192 Hz) to 15 (2 Hz)
+    Periodic { rate: u8 },
+    /// Fires once a second, right after the clock was updated
+    UpdateEnded,
+}
+
+struct Cmos;
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            Port::<u8>::new(0x70).write(reg | NMI_DISABLE);
+            let value = Port::<u8>::new(0x71).read();
+            Self::enable_nmi();
+            value
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        unsafe {
+            Port::<u8>::new(0x70).write(reg | NMI_DISABLE);
+            Port::<u8>::new(0x71).write(value);
+            Self::enable_nmi();
+        }
+    }
+
+    // Selects status D, which is read-only, so stray accesses to the data port can't do harm
+    unsafe fn enable_nmi() {
+        Port::<u8>::new(0x70).write(STATUS_D);
+    }
+
+    // Raw register values, as they were between two updates
+    fn read_clock(&mut self) -> [u8; 6] {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+        [
+            self.read(YEAR),
+            self.read(MONTH),
+            self.read(DAY),
+            self.read(HOURS),
+            self.read(MINUTES),
+            self.read(SECONDS),
+        ]
+    }
+}
+
+// Register selection is stateful, so this is also locked by the IRQ handler. Only lock it with
+// interrupts disabled.
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos);
+
+static IRQ_HANDLER: Once<()> = Once::new();
+
+/// Reads the current date and time from the RTC
+///
+/// Waits out any update in progress, and rereads until two reads agree so the values can't come
+/// from different seconds. Years are assumed to be in the 2000s.
+pub fn now() -> DateTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_clock();
+        loop {
+            let again = cmos.read_clock();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    });
+
+    decode(raw, status_b)
+}
+
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let binary = status_b & BINARY_MODE != 0;
+    let value = |v: u8| if binary { v } else { from_bcd(v) };
+
+    let [year, month, day, hours, minutes, seconds] = raw;
+    let mut hour = value(hours & !HOUR_PM);
+    if status_b & HOUR_24_MODE == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if hours & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: 2000 + u16::from(value(year)),
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minutes),
+        second: value(seconds),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+/// Makes the RTC raise `interrupt` on IRQ 8, delivered through `event::rtc`
+pub fn enable_interrupt(interrupt: RtcInterrupt) {
+    IRQ_HANDLER.call_once(|| {
+        irq::register(RTC_IRQ, || {
+            let flags = CMOS.lock().read(STATUS_C);
+            event::rtc::update(flags & (PERIODIC_INTERRUPT | UPDATE_ENDED_INTERRUPT));
+        });
+    });
+
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let enable = match interrupt {
+            RtcInterrupt::Periodic { rate } => {
+                assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+                let status_a = cmos.read(STATUS_A);
+                cmos.write(STATUS_A, (status_a & 0xf0) | rate);
+                PERIODIC_INTERRUPT
+            }
+            RtcInterrupt::UpdateEnded => UPDATE_ENDED_INTERRUPT,
+        };
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | enable);
+        // Interrupts stop until status C is read
+        cmos.read(STATUS_C);
+    });
+}
+
+/// Stops the RTC from raising `interrupt`
+pub fn disable_interrupt(interrupt: RtcInterrupt) {
+    let disable = match interrupt {
+        RtcInterrupt::Periodic { .. } => PERIODIC_INTERRUPT,
+        RtcInterrupt::UpdateEnded => UPDATE_ENDED_INTERRUPT,
+    };
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !disable);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    test!(decode_formats {
+        let expected = DateTime { year: 2024, month: 12, day: 31, hour: 23, minute: 59, second: 58 };
+        let bcd_24 = [0x24, 0x12, 0x31, 0x23, 0x59, 0x58];
+        assert_eq!(decode(bcd_24, HOUR_24_MODE), expected);
+        let binary_12 = [24, 12, 31, HOUR_PM | 11, 59, 58];
+        assert_eq!(decode(binary_12, BINARY_MODE), expected);
+
+        let midnight = decode([0x24, 0x01, 0x01, 0x12, 0, 0], 0);
+        assert_eq!(midnight.hour, 0);
+        let noon = decode([0x24, 0x01, 0x01, HOUR_PM | 0x12, 0, 0], 0);
+        assert_eq!(noon.hour, 12);
+    });
+
+    test!(now_is_plausible {
+        let time = now();
+        assert!(time.year >= 2020);
+        assert!(time.month >= 1 && time.month <= 12);
+        assert!(time.day >= 1 && time.day <= 31);
+        assert!(time.hour < 24 && time.minute < 60 && time.second < 60);
+        assert!(now() >= time);
+    });
+}
@@ -0,0 +1,140 @@
+use super::PIT_FREQUENCY;
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, Sub};
+use core::time::Duration;
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// PIT channel 2 is gated by port 0x61 and its output can be read back there, so it can be used
+// for a one-shot countdown without touching the timer interrupt on channel 0
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+const PIT_CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+
+const CALIBRATION_MS: u64 = 10;
+
+static TSC_HZ: Once<u64> = Once::new();
+
+/// Measures the TSC frequency against the PIT, so the first measured duration doesn't have to
+///
+/// Takes about 10 ms. Assumes an invariant TSC, which QEMU and modern CPUs provide.
+pub fn init() {
+    frequency();
+}
+
+fn calibrate(ms: u64) -> u64 {
+    let count = PIT_FREQUENCY * ms / 1000;
+
+    interrupts::without_interrupts(|| unsafe {
+        let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+
+        // Gate channel 2 on, but keep it away from the speaker
+        let saved = speaker.read();
+        speaker.write((saved & !SPEAKER_ENABLE) | CHANNEL_2_GATE);
+
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL_2_ONESHOT);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = _rdtsc();
+        while speaker.read() & CHANNEL_2_OUTPUT == 0 {}
+        let end = _rdtsc();
+
+        speaker.write(saved);
+        (end - start) * 1000 / ms
+    })
+}
+
+/// Returns the measured TSC frequency, measuring it first if `init` hasn't run yet
+pub fn frequency() -> u64 {
+    // An interrupt handler waiting on a calibration it interrupted would spin forever
+    interrupts::without_interrupts(|| *TSC_HZ.call_once(|| calibrate(CALIBRATION_MS)))
+}
+
+/// A point in time read from the TSC, with nanosecond resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(unsafe { _rdtsc() })
+    }
+
+    /// Returns the time since `earlier`, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        cycles_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the raw TSC value
+    pub fn cycles(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        let hz = u128::from(frequency());
+        Instant(self.0 + (duration.as_nanos() * hz / 1_000_000_000) as u64)
+    }
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    let hz = frequency();
+    let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(hz);
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time;
+
+    test!(agrees_with_pit {
+        assert!(frequency() > 0);
+
+        let start = Instant::now();
+        let start_uptime = time::uptime();
+        let start_ticks = time::ticks();
+        while time::ticks() < start_ticks + 10 {
+            x86_64::instructions::hlt();
+        }
+        let elapsed = start.elapsed();
+        let pit_elapsed = time::uptime() - start_uptime;
+
+        // The PIT reading is only accurate to a tick
+        let period = time::tick_period();
+        assert!(elapsed + period >= pit_elapsed && elapsed <= pit_elapsed + period);
+    });
+
+    test!(instants_are_ordered {
+        let first = Instant::now();
+        let second = Instant::now();
+        assert!(second >= first);
+        assert_eq!(first - second, Duration::from_secs(0));
+        assert!(first + Duration::from_millis(1) > first);
+    });
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::{exit_qemu, gdt, interrupts, serial_println, test, QemuExitCode};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    gdt::init();
+    interrupts::init_early_idt();
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !blog_os::panic_message_contains(
+        info,
+        "Exception: General Protection Fault (error code 0x0)",
+    ) {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[Ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop();
+}
+
+#[allow(unreachable_code)]
+mod test {
+    use super::*;
+
+    test!(non_canonical_access_is_reported {
+        // Addresses between the lower and upper halves fault with #GP instead of #PF
+        let ptr = 0x_8000_0000_0000 as *const u64;
+        unsafe { ptr.read_volatile() };
+
+        panic!("Execution continued after general protection fault");
+    });
+}
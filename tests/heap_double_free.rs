@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use blog_os::allocator::{debug::DebugAllocator, linked_list::LinkedListAllocator, Locked};
+use blog_os::{exit_qemu, serial_println, test, QemuExitCode};
+use core::panic::PanicInfo;
+
+const HEAP_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> =
+    DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !blog_os::panic_message_contains(info, "double free") {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[Ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop();
+}
+
+#[allow(unreachable_code)]
+mod test {
+    use super::*;
+
+    test!(detect_double_free {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            ALLOCATOR
+                .inner()
+                .lock()
+                .init(&mut HEAP as *mut Heap as usize, HEAP_SIZE);
+            let ptr = ALLOCATOR.alloc(layout);
+            ALLOCATOR.dealloc(ptr, layout);
+            ALLOCATOR.dealloc(ptr, layout);
+        }
+
+        panic!("Execution continued after freeing twice");
+    });
+}
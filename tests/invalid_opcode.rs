@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![feature(core_intrinsics)]
+
+use blog_os::{exit_qemu, gdt, interrupts, serial_println, test, QemuExitCode};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    gdt::init();
+    interrupts::init_early_idt();
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !blog_os::panic_message_contains(info, "Exception: Invalid Opcode") {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[Ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop();
+}
+
+#[allow(unreachable_code)]
+mod test {
+    use super::*;
+
+    test!(ud2_is_reported {
+        // Compiles to a ud2 instruction
+        core::intrinsics::abort();
+
+        panic!("Execution continued after invalid opcode");
+    });
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use blog_os::allocator::{linked_list::LinkedListAllocator, Locked};
+use blog_os::test;
+use core::panic::PanicInfo;
+use core::ptr;
+
+const HEAP_SIZE: usize = 64 * 1024;
+const SLOTS: usize = 64;
+
+#[repr(C, align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// Simple LCG so the allocation pattern is mixed but reproducible
+fn next_rand(state: &mut u64) -> u64 {
+    *state = state
+        .wrapping_mul(6_364_136_223_846_793_005)
+        .wrapping_add(1_442_695_040_888_963_407);
+    *state >> 33
+}
+
+test!(churn_then_full_heap_alloc {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    let heap_start = unsafe { &mut HEAP as *mut Heap as usize };
+    unsafe { allocator.lock().init(heap_start, HEAP_SIZE) };
+
+    let mut slots: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    let mut state = 42;
+
+    for _ in 0..5000 {
+        let slot = &mut slots[next_rand(&mut state) as usize % SLOTS];
+        match slot.take() {
+            Some((ptr, layout)) => unsafe { allocator.dealloc(ptr, layout) },
+            None => {
+                let size = 1 + next_rand(&mut state) as usize % 512;
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let ptr = unsafe { allocator.alloc(layout) };
+                assert!(!ptr.is_null());
+                unsafe { ptr::write_bytes(ptr, 0xab, size) };
+                *slot = Some((ptr, layout));
+            }
+        }
+    }
+
+    for slot in slots.iter_mut() {
+        if let Some((ptr, layout)) = slot.take() {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+
+    // Every region should have been merged back into one, so the whole heap is allocatable
+    let layout = Layout::from_size_align(HEAP_SIZE, 16).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(ptr as usize, heap_start);
+    unsafe { allocator.dealloc(ptr, layout) };
+});
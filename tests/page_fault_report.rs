@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::vma::KERNEL_SPACE_END;
+use blog_os::{exit_qemu, serial_println, test, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info).unwrap();
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let expected = [
+        "Page Fault",
+        "Cause: write, kernel mode, page not present",
+        "Region: unreserved",
+        "P4[",
+    ];
+    if !expected
+        .iter()
+        .all(|e| blog_os::panic_message_contains(info, e))
+    {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[Ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop();
+}
+
+#[allow(unreachable_code)]
+mod test {
+    use super::*;
+
+    test!(unmapped_write_is_decoded {
+        // Nothing ever gets reserved at the very end of the kernel range
+        let ptr = (KERNEL_SPACE_END - 4096) as *mut u64;
+        unsafe { ptr.write_volatile(42) };
+
+        panic!("Execution continued after page fault");
+    });
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::{exit_qemu, serial_println, test, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info).unwrap();
+    test_main();
+    blog_os::hlt_loop();
+}
+
+// The page fault handler runs on its own stack, so overflowing the kernel stack gets reported as a
+// page fault instead of escalating to a double fault
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !blog_os::panic_message_contains(info, "Exception: Page Fault") {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[Ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop();
+}
+
+#[allow(unreachable_code)]
+mod tests {
+    use super::*;
+
+    test!(stack_overflow_is_a_page_fault {
+        stack_overflow();
+
+        panic!("Execution continued after stack overflow");
+    });
+
+    #[allow(unconditional_recursion)]
+    fn stack_overflow() {
+        stack_overflow();
+    }
+}
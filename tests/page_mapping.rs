@@ -5,18 +5,35 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 
+use blog_os::memory::{
+    self,
+    vma::{Region, RegionKind, ADDRESS_SPACE},
+    MapError, FRAME_ALLOCATOR, MAPPER,
+};
 use blog_os::test;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
-    structures::paging::{page::Size4KiB, MapperAllSizes, Page},
+    structures::paging::{
+        page::{PageRange, Size2MiB, Size4KiB},
+        FrameAllocator, FrameDeallocator, MapperAllSizes, Page, PageSize, PageTableFlags,
+    },
     PhysAddr, VirtAddr,
 };
 
 lazy_static! {
     static ref BOOT_INFO: Mutex<Option<&'static BootInfo>> = Mutex::new(None);
+    // Virtual memory for the range tests
+    static ref TEST_REGION: Region = ADDRESS_SPACE
+        .lock()
+        .reserve(4 * Size4KiB::SIZE, RegionKind::Task, writable())
+        .unwrap();
+}
+
+fn writable() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE
 }
 
 #[panic_handler]
@@ -27,6 +44,7 @@ fn panic(info: &PanicInfo) -> ! {
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info).unwrap();
     *BOOT_INFO.lock() = Some(boot_info);
     test_main();
     blog_os::hlt_loop();
@@ -34,7 +52,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 test!(page_map {
     let boot_info = &*BOOT_INFO.lock().unwrap();
-    let (mut mapper, mut frame_allocator) = blog_os::init(boot_info).unwrap();
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
 
     let addresses = [
         // some code page
@@ -53,15 +74,21 @@ test!(page_map {
     // Should map to physical address of 0x0
     let offset = boot_info.physical_memory_offset;
     assert_eq!(mapper.translate_addr(VirtAddr::new(offset)), Some(PhysAddr::new(0x0)));
+    let window = ADDRESS_SPACE.lock().find(VirtAddr::new(offset)).map(|r| r.kind);
+    assert_eq!(window, Some(RegionKind::PhysicalMemory));
 
     // Create a new page mapping and assert that it's actually mapped to that page
-    let new_page = Page::containing_address(VirtAddr::new(0xdeadbeef));
+    let region = ADDRESS_SPACE
+        .lock()
+        .reserve(Size4KiB::SIZE, RegionKind::Mmio, writable())
+        .unwrap();
+    let new_page = Page::containing_address(region.start);
     let old_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0xb8000));
-    blog_os::memory::create_mapping(
+    memory::create_mapping(
         PhysAddr::new(0xb8000),
         new_page,
-        &mut mapper,
-        &mut frame_allocator,
+        mapper,
+        frame_allocator,
     );
 
     let new_page_ptr: *mut u64 = new_page.start_address().as_mut_ptr();
@@ -69,3 +96,120 @@ test!(page_map {
     unsafe { new_page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
     unsafe { assert_eq!(old_page_ptr.offset(400).read_volatile(), 0x_f021_f077_f065_f04e); }
 });
+
+fn test_pages() -> PageRange {
+    TEST_REGION.pages()
+}
+
+test!(map_unmap_range {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let flags = writable();
+
+    memory::map_range(test_pages(), flags, mapper, frame_allocator).unwrap();
+    for page in test_pages() {
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(page.start_address().as_u64());
+            assert_eq!(ptr.read_volatile(), page.start_address().as_u64());
+        }
+    }
+
+    // Mapping over existing pages fails without touching them. The frame it allocated for the
+    // first page, which is the top of the free stack, is freed exactly once and not handed out
+    // with the error.
+    let top = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap();
+    let top_addr = top.start_address();
+    FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, top);
+    assert_eq!(
+        memory::map_range(test_pages(), flags, mapper, frame_allocator),
+        Err(MapError::PageAlreadyMapped)
+    );
+    assert!(mapper.translate_addr(test_pages().start.start_address()).is_some());
+    let top = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap();
+    assert_eq!(top.start_address(), top_addr);
+    let next = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap();
+    assert_ne!(next.start_address(), top_addr);
+    FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, next);
+    FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, top);
+
+    memory::unmap_and_free_range(test_pages(), mapper, frame_allocator).unwrap();
+    for page in test_pages() {
+        assert_eq!(mapper.translate_addr(page.start_address()), None);
+    }
+});
+
+test!(protect_range {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let writable = writable();
+    let ptr: *mut u64 = test_pages().start.start_address().as_mut_ptr();
+
+    memory::map_range(test_pages(), writable, mapper, frame_allocator).unwrap();
+    unsafe { ptr.write_volatile(0x_1234) };
+
+    memory::protect_range(test_pages(), PageTableFlags::PRESENT, mapper).unwrap();
+    let phys = mapper.translate_addr(VirtAddr::from_ptr(ptr));
+    assert!(phys.is_some());
+    unsafe { assert_eq!(ptr.read_volatile(), 0x_1234) };
+
+    memory::protect_range(test_pages(), writable, mapper).unwrap();
+    assert_eq!(mapper.translate_addr(VirtAddr::from_ptr(ptr)), phys);
+    unsafe { ptr.write_volatile(0x_5678) };
+
+    memory::unmap_and_free_range(test_pages(), mapper, frame_allocator).unwrap();
+});
+
+test!(map_mmio {
+    // Device memory shows up at the returned address, whatever its offset into the page
+    let vga = memory::map_mmio(PhysAddr::new(0xb8008), 16).unwrap();
+    assert_eq!(vga.as_u64() % Size4KiB::SIZE, 8);
+    assert_eq!(ADDRESS_SPACE.lock().find(vga).map(|r| r.kind), Some(RegionKind::Mmio));
+
+    let identity: *const u64 = VirtAddr::new(0xb8008).as_ptr();
+    unsafe {
+        vga.as_mut_ptr::<u64>().write_volatile(0x_f04b_f04f);
+        assert_eq!(identity.read_volatile(), 0x_f04b_f04f);
+    }
+
+    memory::unmap_mmio(vga);
+    assert_eq!(ADDRESS_SPACE.lock().find(vga), None);
+    let mapper = MAPPER.lock();
+    assert_eq!(mapper.as_ref().unwrap().translate_addr(vga), None);
+});
+
+test!(map_huge_page {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let flags = writable();
+
+    let region = ADDRESS_SPACE
+        .lock()
+        .reserve_aligned(Size2MiB::SIZE, Size2MiB::SIZE, RegionKind::Task, flags)
+        .unwrap();
+    let page = Page::<Size2MiB>::containing_address(region.start);
+    memory::map_range(Page::range(page, page + 1), flags, mapper, frame_allocator).unwrap();
+
+    let first = page.start_address();
+    let last = first + (Size2MiB::SIZE - 8);
+    unsafe {
+        first.as_mut_ptr::<u64>().write_volatile(1);
+        last.as_mut_ptr::<u64>().write_volatile(2);
+        assert_eq!(first.as_ptr::<u64>().read_volatile(), 1);
+        assert_eq!(last.as_ptr::<u64>().read_volatile(), 2);
+    }
+
+    // The whole range is backed by one contiguous frame
+    let first_phys = mapper.translate_addr(first).unwrap();
+    assert_eq!(mapper.translate_addr(last), Some(first_phys + (Size2MiB::SIZE - 8)));
+
+    memory::unmap_and_free_range(Page::range(page, page + 1), mapper, frame_allocator).unwrap();
+    assert_eq!(mapper.translate_addr(first), None);
+    ADDRESS_SPACE.lock().release(region.start).unwrap();
+});